@@ -0,0 +1,82 @@
+//! Reserved `__admin/registry` rpc dumping the registry and the config of a node, for live diagnosis
+//! Disabled unless `ZENOH_ADMIN` is set. Zenoh doesn't tell a queryable who sent a query,
+//! so the caller proves it is a trusted peer with a token for the `ADMIN_REGISTRY` audience,
+//! signed with the shared `JWT_SECRET`, whose subject is its zid
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use utils::vars::get_env_var;
+
+/// Reserved rpc answered on `@rpc/__admin/registry/{zid}` by the nodes enabling it
+pub const ADMIN_REGISTRY: &str = "__admin/registry";
+
+/// Peers allowed to call the admin rpc of a node, and the secret their tokens are signed with
+#[derive(Clone)]
+pub struct AdminAccess {
+    secret: Vec<u8>,
+    peers: BTreeSet<String>,
+}
+
+impl AdminAccess {
+    /// Access of the peers of the given zids, with tokens signed with the secret
+    pub fn new<P: Into<String>>(secret: impl Into<Vec<u8>>, peers: impl IntoIterator<Item = P>) -> Self {
+        Self { secret: secret.into(), peers: peers.into_iter().map(Into::into).collect() }
+    }
+
+    /// Access of the zids listed in `ZENOH_ADMIN_PEERS` with `JWT_SECRET`, none unless `ZENOH_ADMIN` is set
+    pub fn from_env() -> Option<Self> {
+        if !get_env_var("ZENOH_ADMIN", false) {
+            return None;
+        }
+        let secret = utils::vars::get_jwt_secret();
+        if secret.is_empty() {
+            tracing::warn!("{}:{} ZENOH_ADMIN set without JWT_SECRET, admin rpc disabled", file!(), line!());
+            return None;
+        }
+        let peers = get_env_var("ZENOH_ADMIN_PEERS", String::new());
+        let access = Self::new(secret, peers.split(',').map(str::trim).filter(|v| !v.is_empty()));
+        if access.peers.is_empty() {
+            tracing::warn!("{}:{} ZENOH_ADMIN set without ZENOH_ADMIN_PEERS, no peer can call the admin rpc", file!(), line!());
+        }
+        Some(access)
+    }
+
+    /// Zid of the trusted peer the token was issued to, none for any other token
+    pub(crate) fn authorize(&self, token: &str) -> Option<String> {
+        utils::jwt::verify_token_for(token, &self.secret, Some(ADMIN_REGISTRY), None).filter(|zid| self.peers.contains(zid))
+    }
+}
+
+/// Token proving the caller is the peer of the zid to the nodes sharing the secret
+pub fn admin_token(zid: &str, secret: &[u8]) -> String {
+    utils::jwt::create_token_for(zid, secret, ADMIN_REGISTRY, zid)
+}
+
+/// Registry and config of a node, replied as JSON by `ADMIN_REGISTRY`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RegistrySnapshot {
+    pub zid: String,
+    // instances of each service, of any version
+    pub services: BTreeMap<String, Vec<String>>,
+    // instances of each versioned service, by `service_key`
+    pub versions: BTreeMap<String, Vec<String>>,
+    pub rpc_timeout_ms: u64,
+    pub service_timeouts_ms: BTreeMap<String, u64>,
+    pub balancer: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let access = AdminAccess::new("secret", ["trusted"]);
+        assert_eq!(access.authorize(&admin_token("trusted", b"secret")).as_deref(), Some("trusted"));
+        assert!(access.authorize(&admin_token("other", b"secret")).is_none());
+        assert!(access.authorize(&admin_token("trusted", b"wrong")).is_none());
+        // tokens of the users of the gateway aren't admin tokens
+        assert!(access.authorize(&utils::jwt::create_token("trusted", b"secret")).is_none());
+        assert!(access.authorize("").is_none());
+    }
+}
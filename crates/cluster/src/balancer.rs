@@ -0,0 +1,138 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use dashmap::DashMap;
+use rand::Rng;
+use zenoh::config::ZenohId;
+
+/// Strategy picking the instance of a service each request is sent to
+/// - `service` is the key of the registry the instances belong to, `{service}/{version}` for versioned requests
+/// - `instances` are the registered instances, sorted
+/// - `hint` is the key of the call, e.g. a session id, set through `RpcOptions::hint`
+pub trait LoadBalancer: Send + Sync {
+    fn select(&self, service: &str, instances: &[ZenohId], hint: Option<&[u8]>) -> Option<ZenohId>;
+
+    /// Name of the strategy, reported by the admin rpc of the node
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Cycles through the instances of each service, the default strategy
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    counters: DashMap<String, AtomicUsize>,
+}
+
+impl LoadBalancer for RoundRobin {
+    fn select(&self, service: &str, instances: &[ZenohId], _hint: Option<&[u8]>) -> Option<ZenohId> {
+        if instances.is_empty() {
+            return None;
+        }
+        let current = match self.counters.get(service) {
+            Some(counter) => counter.fetch_add(1, Ordering::Relaxed),
+            None => self.counters.entry(service.to_string()).or_default().fetch_add(1, Ordering::Relaxed),
+        };
+        instances.get(current % instances.len()).copied()
+    }
+}
+
+/// Picks an instance uniformly at random
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl LoadBalancer for Random {
+    fn select(&self, _service: &str, instances: &[ZenohId], _hint: Option<&[u8]>) -> Option<ZenohId> {
+        if instances.is_empty() {
+            return None;
+        }
+        instances.get(rand::rng().random_range(0..instances.len())).copied()
+    }
+}
+
+/// Sends the calls of the same hint to the same instance, e.g. to keep a session on the instance holding its state
+/// Uses rendezvous hashing, so an instance leaving only moves the hints it held.
+/// Calls without a hint are spread at random
+#[derive(Debug, Default)]
+pub struct ConsistentHash;
+
+impl ConsistentHash {
+    fn score(hint: &[u8], zid: &ZenohId) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hint.hash(&mut hasher);
+        zid.to_le_bytes().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl LoadBalancer for ConsistentHash {
+    fn select(&self, service: &str, instances: &[ZenohId], hint: Option<&[u8]>) -> Option<ZenohId> {
+        match hint {
+            Some(hint) => instances.iter().max_by_key(|zid| Self::score(hint, zid)).copied(),
+            None => Random.select(service, instances, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn zids(count: usize) -> Vec<ZenohId> {
+        (1..=count).map(|v| ZenohId::from_str(&format!("{v:x}")).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let balancer = RoundRobin::default();
+        let instances = zids(3);
+        let selected: Vec<_> = (0..6).filter_map(|_| balancer.select("a", &instances, None)).collect();
+        assert_eq!(selected, [instances.clone(), instances.clone()].concat());
+        // each service has its own position
+        assert_eq!(balancer.select("b", &instances, None), Some(instances[0]));
+        assert_eq!(balancer.select("a", &[], None), None);
+    }
+
+    #[test]
+    fn test_random() {
+        let instances = zids(3);
+        for _ in 0..100 {
+            assert!(instances.contains(&Random.select("a", &instances, None).unwrap()));
+        }
+        assert_eq!(Random.select("a", &[], None), None);
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let instances = zids(4);
+        let selected = ConsistentHash.select("a", &instances, Some(b"session-1")).unwrap();
+        for _ in 0..10 {
+            assert_eq!(ConsistentHash.select("a", &instances, Some(b"session-1")), Some(selected));
+        }
+
+        // removing another instance keeps the hint on its instance
+        let others: Vec<_> = instances.iter().filter(|v| **v != selected).copied().collect();
+        let remaining: Vec<_> = instances.iter().filter(|v| **v != others[0]).copied().collect();
+        assert_eq!(ConsistentHash.select("a", &remaining, Some(b"session-1")), Some(selected));
+
+        // hints are spread over the instances
+        let used: std::collections::BTreeSet<_> = (0..64)
+            .filter_map(|v| ConsistentHash.select("a", &instances, Some(format!("session-{v}").as_bytes())))
+            .collect();
+        assert!(used.len() > 1);
+
+        // an instance leaving only remaps the hints it held
+        for v in 0..64 {
+            let hint = format!("session-{v}");
+            let before = ConsistentHash.select("a", &instances, Some(hint.as_bytes())).unwrap();
+            if before != others[0] {
+                assert_eq!(ConsistentHash.select("a", &remaining, Some(hint.as_bytes())), Some(before));
+            }
+        }
+        assert_eq!(ConsistentHash.select("a", &[], Some(b"session-1")), None);
+    }
+}
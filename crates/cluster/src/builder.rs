@@ -0,0 +1,217 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use traits::app::RpcTrait;
+use utils::vars::get_env_var;
+
+use crate::{
+    admin::AdminAccess,
+    balancer::{LoadBalancer, RoundRobin},
+    channel::{self, QueryableChannel},
+    dedup,
+    limit::{self, OverloadPolicy},
+    Node,
+};
+
+/// Configuration of a `Node`, for embedders setting it in code instead of through the environment
+/// `NodeBuilder::default()` starts from the environment variables read by `Node::new`, each setter overrides one of them
+///
+/// ```ignore
+/// let node = NodeBuilder::default()
+///     .rpc_timeout(Duration::from_secs(2))
+///     .service_timeout("report", Duration::from_secs(30))
+///     .balancer(ConsistentHash)
+///     .build(context, handler)
+///     .await?;
+/// ```
+#[derive(Clone)]
+pub struct NodeBuilder {
+    pub(crate) rpc_timeout: Duration,
+    pub(crate) service_timeouts: HashMap<String, Duration>,
+    pub(crate) balancer: Arc<dyn LoadBalancer>,
+    pub(crate) channel: QueryableChannel,
+    pub(crate) compression: types::Compression,
+    pub(crate) compression_threshold: usize,
+    pub(crate) resync_interval: Option<Duration>,
+    pub(crate) connectivity_interval: Option<Duration>,
+    pub(crate) dedup_capacity: usize,
+    pub(crate) dedup_ttl: Duration,
+    pub(crate) metrics: bool,
+    pub(crate) max_request_size: usize,
+    pub(crate) max_reply_size: usize,
+    pub(crate) max_in_flight: usize,
+    pub(crate) overload_policy: OverloadPolicy,
+    pub(crate) admin: Option<AdminAccess>,
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        let channel_size = get_env_var("ZENOH_QUERYABLE_CHANNEL_SIZE", channel::QUERYABLE_CHANNEL_SIZE);
+        let channel = get_env_var("ZENOH_QUERYABLE_CHANNEL", "fifo".to_string());
+        let channel = QueryableChannel::parse(&channel, channel_size).unwrap_or_else(|_| {
+            tracing::warn!("{}:{} invalid ZENOH_QUERYABLE_CHANNEL {channel}, using fifo", file!(), line!());
+            QueryableChannel::Fifo(channel_size)
+        });
+        let queue_size = get_env_var("ZENOH_OVERLOAD_QUEUE_SIZE", limit::OVERLOAD_QUEUE_SIZE);
+        let policy = get_env_var("ZENOH_OVERLOAD_POLICY", "queue".to_string());
+        let overload_policy = OverloadPolicy::parse(&policy, queue_size).unwrap_or_else(|_| {
+            tracing::warn!("{}:{} invalid ZENOH_OVERLOAD_POLICY {policy}, using queue", file!(), line!());
+            OverloadPolicy::Queue(queue_size)
+        });
+        Self {
+            rpc_timeout: Duration::from_millis(get_env_var("ZENOH_RPC_TIMEOUT", 10 * 1000)),
+            service_timeouts: HashMap::new(),
+            balancer: Arc::new(RoundRobin::default()),
+            channel,
+            compression: get_env_var("ZENOH_COMPRESSION", types::Compression::Lz4),
+            compression_threshold: get_env_var("ZENOH_COMPRESSION_THRESHOLD", 64 * 1024),
+            resync_interval: match get_env_var("ZENOH_LIVELINESS_RESYNC", 30 * 1000) {
+                0 => None,
+                v => Some(Duration::from_millis(v)),
+            },
+            connectivity_interval: match get_env_var("ZENOH_CONNECTIVITY_INTERVAL", 1000) {
+                0 => None,
+                v => Some(Duration::from_millis(v)),
+            },
+            dedup_capacity: get_env_var("ZENOH_DEDUP_CAPACITY", dedup::DEDUP_CAPACITY),
+            dedup_ttl: Duration::from_millis(get_env_var("ZENOH_DEDUP_TTL", dedup::DEDUP_TTL)),
+            metrics: true,
+            max_request_size: get_env_var("ZENOH_MAX_REQUEST_SIZE", crate::MAX_PAYLOAD_SIZE),
+            max_reply_size: get_env_var("ZENOH_MAX_REPLY_SIZE", crate::MAX_PAYLOAD_SIZE),
+            max_in_flight: get_env_var("ZENOH_MAX_IN_FLIGHT", 0),
+            overload_policy,
+            admin: AdminAccess::from_env(),
+        }
+    }
+}
+
+impl NodeBuilder {
+    /// Default timeout of the rpcs sent by the node, `ZENOH_RPC_TIMEOUT` by default
+    pub fn rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.rpc_timeout = timeout;
+        self
+    }
+
+    /// Default timeout of the rpcs sent to the service, overriding `rpc_timeout`
+    pub fn service_timeout(mut self, service: impl Into<String>, timeout: Duration) -> Self {
+        self.service_timeouts.insert(service.into(), timeout);
+        self
+    }
+
+    /// Replaces every per-service timeout
+    pub fn service_timeouts(mut self, service_timeouts: HashMap<String, Duration>) -> Self {
+        self.service_timeouts = service_timeouts;
+        self
+    }
+
+    /// Strategy picking the instance each request is sent to, round robin by default
+    pub fn balancer<B: LoadBalancer + 'static>(mut self, balancer: B) -> Self {
+        self.balancer = Arc::new(balancer);
+        self
+    }
+
+    /// Channel buffering the queries of the services, `ZENOH_QUERYABLE_CHANNEL` by default
+    pub fn channel(mut self, channel: QueryableChannel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Codec of the outgoing payloads of at least `threshold` bytes, `ZENOH_COMPRESSION` by default
+    pub fn compression(mut self, compression: types::Compression, threshold: usize) -> Self {
+        self.compression = compression;
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Interval of the full liveliness resyncs of the registry, none disables them
+    pub fn resync_interval(mut self, interval: Option<Duration>) -> Self {
+        self.resync_interval = interval;
+        self
+    }
+
+    /// Interval of the checks of the links of the session behind `Node::is_connected`, none disables them
+    /// `ZENOH_CONNECTIVITY_INTERVAL` (milliseconds) by default
+    pub fn connectivity_interval(mut self, interval: Option<Duration>) -> Self {
+        self.connectivity_interval = interval;
+        self
+    }
+
+    /// Idempotency keys remembered per service deduplicating its requests, and for how long
+    pub fn dedup(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.dedup_capacity = capacity;
+        self.dedup_ttl = ttl;
+        self
+    }
+
+    /// Whether the rpcs sent by the node are recorded, only effective with the `metrics` feature
+    pub fn metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Limit of the encoded requests the node sends and handles, `ZENOH_MAX_REQUEST_SIZE` by default
+    /// Larger requests fail with `ERROR_CODE_PAYLOAD_TOO_LARGE`, before being sent or decoded
+    pub fn max_request_size(mut self, max_size: usize) -> Self {
+        self.max_request_size = max_size;
+        self
+    }
+
+    /// Limit of the encoded replies the node decodes, `ZENOH_MAX_REPLY_SIZE` by default
+    /// Larger replies fail with `ERROR_CODE_PAYLOAD_TOO_LARGE`
+    pub fn max_reply_size(mut self, max_size: usize) -> Self {
+        self.max_reply_size = max_size;
+        self
+    }
+
+    /// Limit of the handlers the node runs at once across its services, `ZENOH_MAX_IN_FLIGHT` by default, 0 for no limit
+    /// Over the limit, requests are queued or rejected with `ERROR_CODE_OVERLOADED` as the policy says,
+    /// `ZENOH_OVERLOAD_POLICY` (`queue` or `reject`) with `ZENOH_OVERLOAD_QUEUE_SIZE` waiting requests by default
+    pub fn max_in_flight(mut self, limit: usize, policy: OverloadPolicy) -> Self {
+        self.max_in_flight = limit;
+        self.overload_policy = policy;
+        self
+    }
+
+    /// Peers allowed to call the `__admin/registry` rpc of the node, none disables it
+    /// Disabled by default, unless `ZENOH_ADMIN` is set, see `AdminAccess::from_env`
+    pub fn admin(mut self, access: Option<AdminAccess>) -> Self {
+        self.admin = access;
+        self
+    }
+
+    /// Creates the node serving the handler, returns the error of declaring its endpoints
+    pub async fn build<H>(self, context: Arc<H::Context>, handler: H) -> zenoh::Result<Node<H>>
+    where
+        H: RpcTrait + Send + Sync + 'static,
+    {
+        Node::try_from_builder(context, handler, self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let builder = NodeBuilder::default()
+            .rpc_timeout(Duration::from_secs(2))
+            .service_timeout("report", Duration::from_secs(30))
+            .channel(QueryableChannel::Ring(8))
+            .resync_interval(None)
+            .connectivity_interval(Some(Duration::from_millis(100)))
+            .metrics(false)
+            .max_in_flight(4, OverloadPolicy::Reject)
+            .admin(Some(AdminAccess::new("secret", ["peer"])));
+        assert_eq!(builder.rpc_timeout, Duration::from_secs(2));
+        assert_eq!(builder.service_timeouts.get("report"), Some(&Duration::from_secs(30)));
+        assert_eq!(builder.channel, QueryableChannel::Ring(8));
+        assert_eq!(builder.resync_interval, None);
+        assert_eq!(builder.connectivity_interval, Some(Duration::from_millis(100)));
+        assert!(!builder.metrics);
+        assert_eq!((builder.max_in_flight, builder.overload_policy), (4, OverloadPolicy::Reject));
+        assert!(builder.admin.is_some());
+
+        let builder = builder.service_timeouts(HashMap::new());
+        assert!(builder.service_timeouts.is_empty());
+    }
+}
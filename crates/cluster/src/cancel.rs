@@ -0,0 +1,134 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Rpcs handled by a node that their callers can cancel, by request id
+#[derive(Default)]
+pub(crate) struct InFlight {
+    tokens: DashMap<String, CancellationToken>,
+}
+
+impl InFlight {
+    /// Token of a request about to be handled, cancelled when the node stops, once the deadline passes,
+    /// or when its caller cancels the id. A request without id can't be cancelled by its caller
+    pub(crate) fn register(self: &Arc<Self>, parent: &CancellationToken, id: Option<&str>, deadline: Option<i64>) -> Registration {
+        let token = parent.child_token();
+        let id = id.filter(|v| !v.is_empty()).map(|v| {
+            self.tokens.insert(v.to_string(), token.clone());
+            v.to_string()
+        });
+        let timer = deadline.map(|deadline| {
+            let token = token.clone();
+            let wait = Duration::from_millis(deadline.saturating_sub(crate::unix_ms()).max(0) as u64);
+            tokio::spawn(async move {
+                tokio::time::sleep(wait).await;
+                token.cancel();
+            })
+        });
+        Registration { in_flight: self.clone(), id, token, timer }
+    }
+
+    /// Cancels the request of the id, if it is still being handled
+    pub(crate) fn cancel(&self, id: &str) {
+        if let Some((_, token)) = self.tokens.remove(id) {
+            tracing::debug!("{}:{} request {id} cancelled by its caller", file!(), line!());
+            token.cancel();
+        }
+    }
+}
+
+/// Cancellation of a request being handled, forgotten once the request is done
+pub(crate) struct Registration {
+    in_flight: Arc<InFlight>,
+    id: Option<String>,
+    token: CancellationToken,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Registration {
+    pub(crate) fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(id) = &self.id {
+            self.in_flight.tokens.remove(id);
+        }
+        if let Some(timer) = &self.timer {
+            timer.abort();
+        }
+    }
+}
+
+/// Cancels an rpc on the instances of the service if dropped before being disarmed,
+/// e.g. when the caller gives up on the reply
+pub(crate) struct CancelOnDrop {
+    session: zenoh::Session,
+    key_expr: String,
+    id: String,
+    armed: bool,
+}
+
+impl CancelOnDrop {
+    pub(crate) fn new(session: zenoh::Session, key_expr: String, id: String) -> Self {
+        Self { session, key_expr, id, armed: true }
+    }
+
+    /// The rpc completed, nothing to cancel
+    pub(crate) fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let session = self.session.clone();
+        let key_expr = std::mem::take(&mut self.key_expr);
+        let id = std::mem::take(&mut self.id);
+        runtime.spawn(async move {
+            if let Err(e) = session.put(key_expr, id).await {
+                tracing::error!("{}:{} {}", file!(), line!(), e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_register() {
+        let in_flight = Arc::new(InFlight::default());
+        let shutdown = CancellationToken::new();
+
+        let registration = in_flight.register(&shutdown, Some("a"), None);
+        in_flight.cancel("b");
+        assert!(!registration.token().is_cancelled());
+        in_flight.cancel("a");
+        assert!(registration.token().is_cancelled());
+
+        // the id is forgotten once the request is done
+        let registration = in_flight.register(&shutdown, Some("c"), None);
+        drop(registration);
+        assert!(in_flight.tokens.is_empty());
+
+        // the deadline and the shutdown of the node cancel the request
+        let registration = in_flight.register(&shutdown, None, Some(crate::unix_ms() + 20));
+        tokio::time::timeout(Duration::from_secs(1), registration.token().cancelled()).await.unwrap();
+        let registration = in_flight.register(&shutdown, Some(""), None);
+        assert!(in_flight.tokens.is_empty());
+        shutdown.cancel();
+        assert!(registration.token().is_cancelled());
+    }
+}
@@ -0,0 +1,72 @@
+use zenoh::{
+    handlers::{FifoChannel, FifoChannelHandler, RingChannel, RingChannelHandler},
+    query::{Query, Queryable},
+    Session,
+};
+
+/// Channel buffering the queries of the rpc queryable until the node picks them up
+/// - `Fifo` keeps every query, once full it blocks the delivery of the session,
+///   which pushes back on the network but also stalls the other traffic of the session
+/// - `Ring` never blocks, once full it drops the oldest queries, their callers time out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryableChannel {
+    Fifo(usize),
+    Ring(usize),
+}
+
+/// Default capacity of the queryable channel, the one zenoh uses for its own fifo channels
+pub const QUERYABLE_CHANNEL_SIZE: usize = 256;
+
+impl Default for QueryableChannel {
+    fn default() -> Self {
+        QueryableChannel::Fifo(QUERYABLE_CHANNEL_SIZE)
+    }
+}
+
+impl QueryableChannel {
+    /// Channel of the kind (`fifo` or `ring`) with the given capacity
+    pub fn parse(kind: &str, size: usize) -> Result<Self, types::Error> {
+        match kind.to_lowercase().as_str() {
+            "fifo" | "" => Ok(QueryableChannel::Fifo(size)),
+            "ring" => Ok(QueryableChannel::Ring(size)),
+            _ => Err(types::ERROR_CODE_DESERIALIZE.into()),
+        }
+    }
+
+    /// Declares the queryable on the key expression with this channel
+    pub(crate) async fn declare(&self, session: &Session, key_expr: String) -> zenoh::Result<QueryReceiver> {
+        let queryable = session.declare_queryable(key_expr).complete(true);
+        Ok(match *self {
+            QueryableChannel::Fifo(size) => QueryReceiver::Fifo(queryable.with(FifoChannel::new(size)).await?),
+            QueryableChannel::Ring(size) => QueryReceiver::Ring(queryable.with(RingChannel::new(size)).await?),
+        })
+    }
+}
+
+/// Queryable declared with either channel
+pub(crate) enum QueryReceiver {
+    Fifo(Queryable<FifoChannelHandler<Query>>),
+    Ring(Queryable<RingChannelHandler<Query>>),
+}
+
+impl QueryReceiver {
+    pub(crate) async fn recv_async(&self) -> zenoh::Result<Query> {
+        match self {
+            QueryReceiver::Fifo(v) => v.recv_async().await,
+            QueryReceiver::Ring(v) => v.recv_async().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(QueryableChannel::parse("fifo", 16).unwrap(), QueryableChannel::Fifo(16));
+        assert_eq!(QueryableChannel::parse("RING", 16).unwrap(), QueryableChannel::Ring(16));
+        assert!(QueryableChannel::parse("lifo", 16).is_err());
+        assert_eq!(QueryableChannel::default(), QueryableChannel::Fifo(QUERYABLE_CHANNEL_SIZE));
+    }
+}
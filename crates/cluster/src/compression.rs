@@ -0,0 +1,63 @@
+use std::io::Read;
+
+use types::Compression;
+
+/// Compresses the payload with the codec, `Compression::None` returns it as is
+pub fn compress(codec: Compression, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    match codec {
+        Compression::None => Ok(payload.to_vec()),
+        Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(payload)),
+        Compression::Zstd => zstd::encode_all(payload, zstd::DEFAULT_COMPRESSION_LEVEL),
+    }
+}
+
+/// Restores a payload compressed by `compress` with the same codec
+/// Fails with `ErrorKind::FileTooLarge` once the payload exceeds `max_size` bytes, without decompressing the rest
+pub fn decompress(codec: Compression, payload: &[u8], max_size: usize) -> std::io::Result<Vec<u8>> {
+    let payload = match codec {
+        Compression::None => payload.to_vec(),
+        Compression::Lz4 => {
+            // the size of the payload is prepended by `compress`, as a little endian u32
+            let size = payload.get(..4).map_or(0, |v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]) as usize);
+            if size > max_size {
+                return Err(too_large(max_size));
+            }
+            lz4_flex::decompress_size_prepended(payload)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        }
+        Compression::Zstd => {
+            let mut decoded = Vec::new();
+            zstd::Decoder::new(payload)?.take(max_size as u64 + 1).read_to_end(&mut decoded)?;
+            decoded
+        }
+    };
+    if payload.len() > max_size {
+        return Err(too_large(max_size));
+    }
+    Ok(payload)
+}
+
+fn too_large(max_size: usize) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::FileTooLarge, format!("payload exceeds {max_size} bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let payload = "micromesh ".repeat(1000).into_bytes();
+        for codec in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let compressed = compress(codec, &payload).unwrap();
+            if codec != Compression::None {
+                assert!(compressed.len() < payload.len());
+            }
+            assert_eq!(decompress(codec, &compressed, payload.len()).unwrap(), payload);
+            // a payload over the limit is rejected, however well it compresses
+            let error = decompress(codec, &compressed, payload.len() - 1).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::FileTooLarge);
+        }
+        assert!(decompress(Compression::Lz4, b"invalid", usize::MAX).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+/// Reply of a handled request as sent on the wire, the encoded `ClusterResponse` or the encoded `types::Error`
+pub(crate) type EncodedReply = Result<Vec<u8>, Vec<u8>>;
+
+/// Replies of the request of an idempotency key, set once by whichever request with the key runs first
+pub(crate) type DedupEntry = Arc<OnceCell<Arc<Vec<EncodedReply>>>>;
+
+/// Default number of idempotency keys remembered per service
+pub(crate) const DEDUP_CAPACITY: usize = 10_000;
+
+/// Default time an idempotency key is remembered, in milliseconds
+pub(crate) const DEDUP_TTL: u64 = 5 * 60 * 1000;
+
+struct Entries {
+    entries: HashMap<String, (Instant, DedupEntry)>,
+    // keys by insertion, to evict the expired and the oldest ones
+    order: VecDeque<(Instant, String)>,
+}
+
+/// Bounded cache of the idempotency keys recently handled by a service, and their replies
+/// Keys are forgotten after the ttl, or earlier once more than `capacity` keys are remembered
+pub(crate) struct DedupCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl DedupCache {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            entries: Mutex::new(Entries { entries: HashMap::new(), order: VecDeque::new() }),
+        }
+    }
+
+    /// Entry of the key, a new one unless the key is remembered
+    /// Requests with the same key share the entry, so a duplicate arriving while the first runs waits for its replies
+    pub(crate) fn entry(&self, key: &str) -> DedupEntry {
+        self.entry_at(key, Instant::now())
+    }
+
+    fn entry_at(&self, key: &str, now: Instant) -> DedupEntry {
+        let Ok(mut guard) = self.entries.lock() else {
+            return DedupEntry::default();
+        };
+        let Entries { entries, order } = &mut *guard;
+        while let Some((inserted, oldest)) = order.front() {
+            if now.duration_since(*inserted) < self.ttl && entries.len() < self.capacity {
+                break;
+            }
+            // a key inserted again after expiring has a newer instant, and is kept
+            if entries.get(oldest).is_some_and(|(v, _)| v == inserted) {
+                entries.remove(oldest);
+            }
+            order.pop_front();
+        }
+        if let Some((inserted, entry)) = entries.get(key)
+            && now.duration_since(*inserted) < self.ttl
+        {
+            return entry.clone();
+        }
+        let entry = DedupEntry::default();
+        entries.insert(key.to_string(), (now, entry.clone()));
+        order.push_back((now, key.to_string()));
+        entry
+    }
+}
+
+/// Idempotency keys recently handled by a service deduplicating its requests, rpcs and pushes apart
+pub(crate) struct Dedup {
+    pub(crate) rpc: DedupCache,
+    pub(crate) push: DedupCache,
+}
+
+impl Dedup {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self { rpc: DedupCache::new(capacity, ttl), push: DedupCache::new(capacity, ttl) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_entry() {
+        let cache = DedupCache::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        let first = cache.entry_at("a", now);
+        first.get_or_init(|| async { Arc::new(vec![Ok(vec![1])]) }).await;
+        // a duplicate gets the replies of the first request
+        let duplicate = cache.entry_at("a", now);
+        let replies = duplicate.get_or_init(|| async { Arc::new(vec![Ok(vec![2])]) }).await;
+        assert_eq!(replies.as_slice(), &[Ok(vec![1])]);
+
+        // the oldest key is forgotten beyond the capacity
+        cache.entry_at("b", now);
+        cache.entry_at("c", now);
+        assert!(!cache.entry_at("a", now).initialized());
+
+        // and any key after the ttl
+        let entry = cache.entry_at("d", now);
+        entry.get_or_init(|| async { Arc::new(Vec::new()) }).await;
+        assert!(cache.entry_at("d", now).initialized());
+        assert!(!cache.entry_at("d", now + Duration::from_secs(61)).initialized());
+    }
+}
@@ -0,0 +1,150 @@
+//! Key expressions of the endpoints of the services, built and parsed here so declaring and calling sides agree
+//! - `@rpc/{service}[/{version}]/{zid}` rpc queryable
+//! - `@chl/{service}/{zid}` push subscriber
+//! - `@ack/{service}/{zid}` queryable of the pushes waiting for an acknowledgement
+//! - `@health/{service}/{zid}` health queryable
+//! - `@cancel/{service}/{zid}` subscriber of the ids of the rpcs their callers gave up on
+//! - `@live/{service}[/{version}]/{zid}` liveliness token announcing the instance
+
+use std::str::FromStr;
+
+use zenoh::config::ZenohId;
+
+/// Key expression of every liveliness token
+pub const LIVELINESS: &str = "@live/**";
+
+/// Endpoint of the instances of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Rpc,
+    Push,
+    PushAck,
+    Health,
+    Cancel,
+    Live,
+}
+
+impl Channel {
+    pub const fn prefix(self) -> &'static str {
+        match self {
+            Channel::Rpc => "@rpc",
+            Channel::Push => "@chl",
+            Channel::PushAck => "@ack",
+            Channel::Health => "@health",
+            Channel::Cancel => "@cancel",
+            Channel::Live => "@live",
+        }
+    }
+
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        [Channel::Rpc, Channel::Push, Channel::PushAck, Channel::Health, Channel::Cancel, Channel::Live]
+            .into_iter()
+            .find(|v| v.prefix() == prefix)
+    }
+
+    /// Endpoint of the instance, `key` is the service or its `service_key` for the versioned channels
+    pub fn instance(self, key: &str, zid: &ZenohId) -> String {
+        format!("{}/{key}/{zid}", self.prefix())
+    }
+
+    /// Endpoints of every instance of the key
+    pub fn instances(self, key: &str) -> String {
+        format!("{}/{key}/*", self.prefix())
+    }
+
+    /// Endpoints of every instance of the service, of any version
+    pub fn all_versions(self, service: &str) -> String {
+        format!("{}/{service}/**", self.prefix())
+    }
+
+    /// Endpoint of the instance, whatever the version it serves
+    pub fn instance_any_version(self, service: &str, zid: &ZenohId) -> String {
+        format!("{}/{service}/**/{zid}", self.prefix())
+    }
+}
+
+/// Key of the service in the key expressions of its instances, `{service}/{version}` for versioned services
+pub fn service_key(service: &str, version: &str) -> String {
+    if version.is_empty() {
+        service.to_string()
+    } else {
+        format!("{service}/{version}")
+    }
+}
+
+/// Endpoint of an instance parsed from its key expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub channel: Channel,
+    pub service: String,
+    // empty for unversioned services
+    pub version: String,
+    pub zid: ZenohId,
+}
+
+/// Parses a key expression `{prefix}/{service}[/{version}]/{zid}` of a known channel
+pub fn parse(key_expr: &str) -> Option<Endpoint> {
+    let components: Vec<_> = key_expr.split('/').collect();
+    let (prefix, service, version, zid) = match components.as_slice() {
+        [prefix, service, zid] => (*prefix, *service, "", *zid),
+        [prefix, service, version, zid] => (*prefix, *service, *version, *zid),
+        _ => return None,
+    };
+    let channel = Channel::from_prefix(prefix)?;
+    let zid = match ZenohId::from_str(zid) {
+        Ok(v) => v,
+        Err(_) => {
+            tracing::error!("{}:{} Invalid zid {zid}", file!(), line!());
+            return None;
+        }
+    };
+    Some(Endpoint { channel, service: service.to_string(), version: version.to_string(), zid })
+}
+
+/// Parses the key expression of a liveliness token, `None` for the endpoints of the other channels
+pub fn parse_live(key_expr: &str) -> Option<Endpoint> {
+    parse(key_expr).filter(|v| v.channel == Channel::Live)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert!(parse("@live/test_service/0123456789ABCDEF").is_none());
+
+        let zid = ZenohId::default();
+        let endpoint = parse(&format!("@live/test_service/{zid}")).unwrap();
+        assert_eq!(endpoint, Endpoint { channel: Channel::Live, service: "test_service".to_string(), version: String::new(), zid });
+
+        let endpoint = parse(&format!("@live/test_service/v2/{zid}")).unwrap();
+        assert_eq!(endpoint.service, "test_service");
+        assert_eq!(endpoint.version, "v2");
+        assert!(parse(&format!("@live/a/b/c/{zid}")).is_none());
+        assert!(parse(&format!("@other/test_service/{zid}")).is_none());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let zid = ZenohId::default();
+        for channel in [Channel::Rpc, Channel::Push, Channel::PushAck, Channel::Health, Channel::Cancel, Channel::Live] {
+            let endpoint = parse(&channel.instance(&service_key("orders", "v1"), &zid)).unwrap();
+            assert_eq!((endpoint.channel, endpoint.service.as_str(), endpoint.version.as_str()), (channel, "orders", "v1"));
+            assert_eq!(parse(&channel.instance("orders", &zid)).unwrap().version, "");
+        }
+        assert_eq!(Channel::Rpc.instances("orders/v1"), "@rpc/orders/v1/*");
+        assert_eq!(Channel::Rpc.all_versions("orders"), "@rpc/orders/**");
+        assert_eq!(Channel::Rpc.instance_any_version("orders", &zid), format!("@rpc/orders/**/{zid}"));
+    }
+
+    #[test]
+    fn test_parse_live() {
+        let zid = ZenohId::default();
+        assert!(parse_live(&format!("@rpc/foo/{zid}")).is_none());
+        assert!(parse_live(&format!("@chl/foo/{zid}")).is_none());
+        assert!(parse_live(&format!("@rpc/foo/v1/{zid}")).is_none());
+        let endpoint = parse_live(&format!("@live/foo/{zid}")).unwrap();
+        assert_eq!((endpoint.channel, endpoint.service.as_str(), endpoint.zid), (Channel::Live, "foo", zid));
+    }
+}
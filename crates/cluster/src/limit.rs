@@ -0,0 +1,112 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// What a node running its limit of concurrent handlers does with another request
+/// - `Reject` replies `ERROR_CODE_OVERLOADED` right away, the caller may retry another instance
+/// - `Queue` waits for a handler to finish, up to the given number of waiting requests, the next ones are rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    Reject,
+    Queue(usize),
+}
+
+/// Default number of requests waiting for a handler with the `Queue` policy
+pub const OVERLOAD_QUEUE_SIZE: usize = 1024;
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        OverloadPolicy::Queue(OVERLOAD_QUEUE_SIZE)
+    }
+}
+
+impl OverloadPolicy {
+    /// Policy of the kind (`reject` or `queue`), queuing up to `queue_size` requests
+    pub fn parse(kind: &str, queue_size: usize) -> Result<Self, types::Error> {
+        match kind.to_lowercase().as_str() {
+            "queue" | "" => Ok(OverloadPolicy::Queue(queue_size)),
+            "reject" => Ok(OverloadPolicy::Reject),
+            _ => Err(types::ERROR_CODE_DESERIALIZE.into()),
+        }
+    }
+}
+
+/// Limit of the handlers a node runs at once, across its services
+pub(crate) struct Limiter {
+    // none when unlimited
+    semaphore: Option<Arc<Semaphore>>,
+    policy: OverloadPolicy,
+    waiting: AtomicUsize,
+}
+
+impl Limiter {
+    /// Limiter of `max_in_flight` concurrent handlers, 0 for no limit
+    pub(crate) fn new(max_in_flight: usize, policy: OverloadPolicy) -> Self {
+        Self {
+            semaphore: (max_in_flight > 0).then(|| Arc::new(Semaphore::new(max_in_flight))),
+            policy,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Permit to run a handler, held until it finishes, `ERROR_CODE_OVERLOADED` when over the limit
+    pub(crate) async fn acquire(&self) -> types::Result<Option<OwnedSemaphorePermit>> {
+        let Some(semaphore) = &self.semaphore else {
+            return Ok(None);
+        };
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        let OverloadPolicy::Queue(queue_size) = self.policy else {
+            return Err(types::ERROR_CODE_OVERLOADED.into());
+        };
+        if self.waiting.fetch_add(1, Ordering::AcqRel) >= queue_size {
+            self.waiting.fetch_sub(1, Ordering::AcqRel);
+            return Err(types::ERROR_CODE_OVERLOADED.into());
+        }
+        let permit = semaphore.clone().acquire_owned().await;
+        self.waiting.fetch_sub(1, Ordering::AcqRel);
+        // the semaphore is never closed
+        permit.map(Some).map_err(|_| types::ERROR_CODE_OVERLOADED.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(OverloadPolicy::parse("reject", 8).unwrap(), OverloadPolicy::Reject);
+        assert_eq!(OverloadPolicy::parse("Queue", 8).unwrap(), OverloadPolicy::Queue(8));
+        assert!(OverloadPolicy::parse("drop", 8).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_acquire() {
+        assert!(Limiter::new(0, OverloadPolicy::Reject).acquire().await.unwrap().is_none());
+
+        let limiter = Limiter::new(1, OverloadPolicy::Reject);
+        let permit = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.acquire().await.unwrap_err().code, types::ERROR_CODE_OVERLOADED.0);
+        drop(permit);
+        assert!(limiter.acquire().await.unwrap().is_some());
+
+        // one request waits for the permit, the next one is rejected
+        let limiter = Arc::new(Limiter::new(1, OverloadPolicy::Queue(1)));
+        let permit = limiter.acquire().await.unwrap();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.map(|v| v.is_some()) }
+        });
+        while limiter.waiting.load(Ordering::Acquire) == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(limiter.acquire().await.unwrap_err().code, types::ERROR_CODE_OVERLOADED.0);
+        drop(permit);
+        assert!(waiting.await.unwrap().unwrap());
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use zenoh::query::{ConsolidationMode, QueryTarget};
+
+/// Query settings of a single call of `Node::rpc_with_options`
+/// The default sends the query to one instance picked by the load balancer of the node, like `Node::rpc`.
+/// Any other target queries every instance of the service and returns the first reply
+#[derive(Debug, Clone)]
+pub struct RpcOptions {
+    pub target: QueryTarget,
+    pub consolidation: ConsolidationMode,
+    // falls back to the default timeout of the service when unset
+    pub timeout: Option<Duration>,
+    // whether requests for a version no instance serves go to any instance instead of failing
+    pub version_fallback: bool,
+    // key passed to the load balancer, e.g. a session id to keep on the same instance
+    pub hint: Option<Vec<u8>>,
+}
+
+impl Default for RpcOptions {
+    fn default() -> Self {
+        Self {
+            target: QueryTarget::BestMatching,
+            consolidation: ConsolidationMode::Auto,
+            timeout: None,
+            version_fallback: true,
+            hint: None,
+        }
+    }
+}
+
+impl RpcOptions {
+    pub fn target(mut self, target: QueryTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn consolidation(mut self, consolidation: ConsolidationMode) -> Self {
+        self.consolidation = consolidation;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn version_fallback(mut self, version_fallback: bool) -> Self {
+        self.version_fallback = version_fallback;
+        self
+    }
+
+    pub fn hint(mut self, hint: impl Into<Vec<u8>>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+/// Controls how `Node::rpc_with_retry` retries transient failures
+/// Delays grow as `base_delay * multiplier^(attempt - 1)`, capped at `max_delay`
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Total number of attempts including the first call, at least 1
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Delay to wait after the given failed attempt (starting at 1)
+    /// Saturates at `max_delay`, also when the multiplier set directly is NaN, infinite or negative
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        Duration::try_from_secs_f64(self.base_delay.as_secs_f64() * factor)
+            .map_or(self.max_delay, |v| v.min(self.max_delay))
+    }
+
+    /// Only timeouts and missing services are transient, application errors are returned as is
+    pub fn is_retryable(error: &types::Error) -> bool {
+        error.code == types::ERROR_CODE_RPC_TIMEOUT.0 || error.code == types::ERROR_CODE_SERVICE_NOT_FOUND.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::default()
+            .base_delay(Duration::from_millis(100))
+            .multiplier(2.0)
+            .max_delay(Duration::from_millis(500));
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(4), Duration::from_millis(500));
+        assert_eq!(policy.delay(100), Duration::from_millis(500));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(500));
+
+        let policy = RetryPolicy { multiplier: f64::NAN, ..policy };
+        assert_eq!(policy.delay(2), Duration::from_millis(500));
+        let policy = RetryPolicy { multiplier: f64::INFINITY, ..policy };
+        assert_eq!(policy.delay(2), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(RetryPolicy::is_retryable(&types::ERROR_CODE_RPC_TIMEOUT.into()));
+        assert!(RetryPolicy::is_retryable(&types::ERROR_CODE_SERVICE_NOT_FOUND.into()));
+        assert!(!RetryPolicy::is_retryable(&types::ERROR_CODE_INTERNAL_ERROR.into()));
+    }
+}
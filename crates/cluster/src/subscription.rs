@@ -0,0 +1,36 @@
+use types::ClusterRequest;
+use zenoh::{handlers::FifoChannelHandler, pubsub::Subscriber, sample::Sample};
+
+/// Receives the messages pushed to any instance of a service, alongside the instances handling them
+/// Returned by `Node::subscribe_pushes` and `Node::subscribe_user_pushes`, dropping it stops the subscription too
+pub struct PushSubscriber {
+    subscriber: Subscriber<FifoChannelHandler<Sample>>,
+    // messages over this size are skipped, in bytes
+    max_size: usize,
+    // only the messages of this user are received when set
+    user_id: Option<String>,
+}
+
+impl PushSubscriber {
+    pub(crate) fn new(subscriber: Subscriber<FifoChannelHandler<Sample>>, max_size: usize, user_id: Option<String>) -> Self {
+        Self { subscriber, max_size, user_id }
+    }
+
+    /// Waits for the next pushed message, none once the subscription is closed
+    /// Messages that fail to decode, over the request size limit of the node, or of another user, are skipped
+    pub async fn recv(&self) -> Option<ClusterRequest> {
+        loop {
+            let sample = self.subscriber.recv_async().await.ok()?;
+            match crate::decode_envelope(&sample.payload().to_bytes(), self.max_size) {
+                Ok(request) if self.user_id.is_none() || request.user_id == self.user_id => return Some(request),
+                Ok(_) => {}
+                Err(e) => tracing::debug!("{}:{} {}", file!(), line!(), e),
+            }
+        }
+    }
+
+    /// Stops receiving the messages of the service
+    pub async fn undeclare(self) -> zenoh::Result<()> {
+        self.subscriber.undeclare().await
+    }
+}
@@ -0,0 +1,54 @@
+//! Rpc metrics reported through the `metrics` facade, compiled in with the `metrics` feature
+//! - `rpc_calls_total` counter, labelled by `service` and `outcome` (`ok`, `timeout`, `not_found`, `internal`)
+//! - `rpc_latency_seconds` histogram, labelled by `service`
+//! - `session_connected` gauge, 1 while the session of the node has a link to a peer or a router, 0 otherwise
+
+use std::time::Instant;
+
+use types::ClusterResponse;
+
+pub const RPC_CALLS_TOTAL: &str = "rpc_calls_total";
+pub const RPC_LATENCY_SECONDS: &str = "rpc_latency_seconds";
+pub const SESSION_CONNECTED: &str = "session_connected";
+
+/// Outcome label of an rpc result
+pub fn outcome(result: &types::Result<ClusterResponse>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(e) if e.code == types::ERROR_CODE_RPC_TIMEOUT.0 => "timeout",
+        Err(e) if e.code == types::ERROR_CODE_SERVICE_NOT_FOUND.0 => "not_found",
+        Err(_) => "internal",
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_rpc(service: &str, started: Instant, result: &types::Result<ClusterResponse>) {
+    let service = service.to_string();
+    metrics::counter!(RPC_CALLS_TOTAL, "service" => service.clone(), "outcome" => outcome(result)).increment(1);
+    metrics::histogram!(RPC_LATENCY_SECONDS, "service" => service).record(started.elapsed().as_secs_f64());
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_rpc(_service: &str, _started: Instant, _result: &types::Result<ClusterResponse>) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_connected(connected: bool) {
+    metrics::gauge!(SESSION_CONNECTED).set(if connected { 1.0 } else { 0.0 });
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_connected(_connected: bool) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome() {
+        let response = ClusterResponse { zid: "".to_string(), status: 200, payload: None, content_type: None, headers: Vec::new() };
+        assert_eq!(outcome(&Ok(response)), "ok");
+        assert_eq!(outcome(&Err(types::ERROR_CODE_RPC_TIMEOUT.into())), "timeout");
+        assert_eq!(outcome(&Err(types::ERROR_CODE_SERVICE_NOT_FOUND.into())), "not_found");
+        assert_eq!(outcome(&Err(types::ERROR_CODE_DESERIALIZE.into())), "internal");
+    }
+}
@@ -0,0 +1,133 @@
+//! Nodes on an isolated zenoh network for the tests of the services, enabled by the `test-util` feature
+//!
+//! ```ignore
+//! let nodes = testkit::spawn_nodes(vec![OrdersHandler, BillingHandler]).await?;
+//! assert!(testkit::await_services(&nodes[0], &["orders", "billing"], Duration::from_secs(5)).await);
+//! // ... calls between the nodes
+//! testkit::shutdown(nodes).await;
+//! ```
+
+use std::{sync::Arc, time::Duration};
+
+use serde_json::json;
+use traits::app::{ContextTrait, RpcTrait};
+
+use crate::Node;
+
+/// Context of the test nodes, only holding their session
+pub struct TestContext {
+    session: zenoh::Session,
+}
+
+impl From<zenoh::Session> for TestContext {
+    fn from(session: zenoh::Session) -> Self {
+        Self { session }
+    }
+}
+
+impl ContextTrait for TestContext {
+    fn session(&self) -> &zenoh::Session {
+        &self.session
+    }
+}
+
+/// Isolated zenoh network: its sessions don't scout, they only connect to each other over the loopback,
+/// so the tests running in parallel don't see each other's services
+#[derive(Default)]
+pub struct Network {
+    endpoints: Vec<String>,
+}
+
+impl Network {
+    /// Opens a session joining the network, connected to every session opened before it
+    pub async fn session(&mut self) -> zenoh::Result<zenoh::Session> {
+        let endpoint = format!("tcp/127.0.0.1:{}", free_port()?);
+        let mut config = zenoh::Config::default();
+        config.insert_json5("mode", &json!("peer").to_string())?;
+        config.insert_json5("scouting/multicast/enabled", "false")?;
+        config.insert_json5("listen/endpoints", &json!([endpoint]).to_string())?;
+        config.insert_json5("connect/endpoints", &json!(self.endpoints).to_string())?;
+        let session = zenoh::open(config).await?;
+        self.endpoints.push(endpoint);
+        Ok(session)
+    }
+}
+
+/// Port free on the loopback, for the listener of a session
+fn free_port() -> std::io::Result<u16> {
+    Ok(std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+/// Spins up one node per handler on a new isolated network, each with its own session
+/// The nodes may not see each other's services yet, see `await_services`
+pub async fn spawn_nodes<H>(handlers: impl IntoIterator<Item = H>) -> zenoh::Result<Vec<Node<H>>>
+where
+    H: RpcTrait + Send + Sync + 'static,
+    H::Context: From<zenoh::Session>,
+{
+    let mut network = Network::default();
+    let mut nodes = Vec::new();
+    for handler in handlers {
+        let context = Arc::new(H::Context::from(network.session().await?));
+        nodes.push(Node::try_new(context, handler).await?);
+    }
+    Ok(nodes)
+}
+
+/// Waits until the registry of the node has an instance of each service, false if it has not within the timeout
+pub async fn await_services<H>(node: &Node<H>, services: &[&str], timeout: Duration) -> bool
+where
+    H: RpcTrait + Send + Sync + 'static,
+{
+    node.wait_ready(services, timeout).await
+}
+
+/// Stops the nodes, each one once its services are no longer announced
+pub async fn shutdown<H>(nodes: Vec<Node<H>>)
+where
+    H: RpcTrait + Send + Sync + 'static,
+{
+    for node in nodes {
+        node.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct NamedHandler(&'static str);
+
+    #[async_trait::async_trait]
+    impl RpcTrait for NamedHandler {
+        type Context = TestContext;
+        type Params = ();
+        type Result = String;
+
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        async fn rpc_call(&self, _context: Arc<Self::Context>, _params: Self::Params) -> Self::Result {
+            self.0.to_string()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_spawn_nodes() {
+        let nodes = spawn_nodes([NamedHandler("testkit_a"), NamedHandler("testkit_b")]).await.unwrap();
+        assert!(await_services(&nodes[0], &["testkit_a", "testkit_b"], Duration::from_secs(5)).await);
+        assert!(!await_services(&nodes[0], &["testkit_missing"], Duration::from_millis(50)).await);
+
+        let request = types::ClusterRequest { payload: bitcode::encode(&()), ..Default::default() };
+        let response = nodes[0].rpc("testkit_b", &request).await.unwrap();
+        assert_eq!(bitcode::decode::<String>(&response.payload.unwrap()).unwrap(), "testkit_b");
+
+        // the nodes of another network don't see them
+        let other = spawn_nodes([NamedHandler("testkit_c")]).await.unwrap();
+        assert!(!await_services(&other[0], &["testkit_a"], Duration::from_millis(500)).await);
+        shutdown(nodes).await;
+        shutdown(other).await;
+    }
+}
@@ -1,16 +1,19 @@
+use std::sync::Arc;
+
 use traits::app::ContextTrait;
 use utils::zenoh;
 
 #[derive(Clone)]
 pub struct AppContext {
-   s: utils::zenoh::Session,
+   // shared with the other contexts of the process
+   s: Arc<utils::zenoh::Session>,
 }
 
 impl AppContext {
-    pub async fn new() -> Self {
-        Self { 
-            s: utils::zenoh_zession::create_session().await,
-        }
+    pub async fn try_new() -> Result<Self, utils::zenoh_zession::SessionError> {
+        Ok(Self {
+            s: utils::zenoh_zession::try_shared_session().await?,
+        })
     }
 }
 
@@ -1,9 +1,11 @@
+use std::{collections::HashMap, convert::Infallible, net::{IpAddr, SocketAddr}, sync::{Arc, LazyLock}, time::Duration};
 
-use std::sync::Arc;
-
-use axum::{body::Bytes, debug_handler, extract::{ws::WebSocket, Path, State, WebSocketUpgrade}, response::IntoResponse};
+use axum::{body::Bytes, debug_handler, extract::{ws::{close_code, CloseFrame, Message, WebSocket}, ConnectInfo, Path, RawQuery, Request, State, WebSocketUpgrade}, http::{HeaderMap, HeaderValue, Method, StatusCode}, middleware::Next, response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response}, Extension};
+use tokio::sync::oneshot;
+use utils::outbox::Outbox;
+use tokio_stream::{Stream, StreamExt};
 use traits::{app::ContextTrait, gateway::{GatewayTrait, GatewayTraitRpcWrapper}};
-use crate::context::AppContext;
+use crate::{context::AppContext, security::{config::TrustedProxies, middleware::{AuthUser, TRUSTED_PROXIES}}, FORWARDED_FOR_HEADER, IDEMPOTENCY_KEY_HEADER, PUSH_ACK_HEADER, REAL_IP_HEADER, REQUEST_ID_HEADER};
 
 
 
@@ -15,35 +17,422 @@ pub type Node = cluster::Node<GatewayTraitRpcWrapper<GatewaytHandler>>;
 #[async_trait::async_trait]
 impl GatewayTrait for GatewaytHandler{
     type Context = AppContext;
-    async fn ping(&self, context: std::sync::Arc<Self::Context> ,zid:String) -> String {
+    async fn ping(&self, context: std::sync::Arc<Self::Context> ,_zid:String) -> String {
         context.session().zid().to_string()
     } 
 }
 
+/// Address of the client, see `forwarded_ip`
+pub(crate) fn client_ip(headers: &HeaderMap, addr: SocketAddr) -> String {
+    forwarded_ip(headers, addr, &TRUSTED_PROXIES)
+}
+
+/// Address of the client, taken from the proxy headers when the peer is a trusted proxy, otherwise from the connection
+/// Proxies append the address of their peer to `X-Forwarded-For`, so the right-most hop that isn't a trusted proxy
+/// is the client, the hops on its left are whatever the client sent
+pub(crate) fn forwarded_ip(headers: &HeaderMap, addr: SocketAddr, proxies: &TrustedProxies) -> String {
+    if !proxies.contains(addr.ip()) {
+        return addr.ip().to_string();
+    }
+    let forwarded = headers.get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect::<Vec<_>>();
+    for hop in forwarded.iter().rev() {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) if proxies.contains(ip) => continue,
+            Ok(ip) => return ip.to_string(),
+            Err(_) => break,
+        }
+    }
+    headers.get(REAL_IP_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<IpAddr>().ok())
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| addr.ip().to_string())
+}
+
+/// Trace id of the request, set by `trace_id_middleware`
+pub(crate) fn trace_id(headers: &HeaderMap) -> String {
+    headers.get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .unwrap_or_default()
+}
+
+/// Idempotency key sent by the client, lets services deduplicating requests replay the reply of a retried request
+pub(crate) fn idempotency_key(headers: &HeaderMap) -> Option<String> {
+    headers.get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Keeps the request id sent by the client, or generates one, as the trace id of the request
+/// The id is forwarded to the services with the request and echoed in the response
+pub async fn trace_id_middleware(mut req: Request, next: Next) -> Response {
+    let trace_id = match req.headers().get(REQUEST_ID_HEADER) {
+        Some(v) if !v.is_empty() => v.clone(),
+        _ => match HeaderValue::from_str(&utils::xid::new().to_string()) {
+            Ok(v) => v,
+            Err(_) => return next.run(req).await,
+        },
+    };
+    req.headers_mut().insert(REQUEST_ID_HEADER, trace_id.clone());
+    let mut response = next.run(req).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, trace_id);
+    response
+}
+
+/// Checks the version segment of the path names an api version, `v` followed by digits, e.g. `v1`,
+/// and the registry has an instance of the service, before building the request
+fn check_path(node: &Node, service: &str, version: &str) -> Result<(), types::Error> {
+    match version.strip_prefix('v') {
+        Some(v) if !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()) => {}
+        _ => return Err(types::ERROR_CODE_INVALID_VERSION.into()),
+    }
+    if node.instances(service).is_empty() {
+        return Err(types::ERROR_CODE_SERVICE_NOT_FOUND.into());
+    }
+    Ok(())
+}
+
+/// Forwards the request as an rpc to the service and replies its response
+/// Replies 400 for a version other than `v{n}`, and 404 right away for a service without instances
+/// The rpc is awaited in the request future, so a client disconnecting drops it and cancels the rpc on the service
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn handler_gateway(
     State(node): State<Arc<Node>>,
     Path((service, version, query)): Path<(String, String, String)>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    user: Option<Extension<AuthUser>>,
+    method: Method,
+    RawQuery(query_string): RawQuery,
+    headers: HeaderMap,
     body: Bytes
 ) -> Result<impl IntoResponse, types::Error> {
+    check_path(&node, &service, &version)?;
     let req = types::ClusterRequest {
         zid: node.zid(),
         version,
         query,
         payload: body.to_vec(), 
+        user_id: user.map(|Extension(AuthUser(v))| v),
+        client_ip: Some(client_ip(&headers, addr)),
+        method: method.to_string(),
+        query_string: query_string.unwrap_or_default(),
+        trace_id: trace_id(&headers),
+        idempotency_key: idempotency_key(&headers),
+        ..Default::default()
     };
     let reply: types::ClusterResponse = node.rpc(&service, &req).await?;
     Ok(reply)
 }
 
+/// Forwards the request as a push, a command handled by the service without a reply
+/// - Replies 202 Accepted once the message is published, without waiting for the service
+/// - With the `x-push-ack` header, waits for the service to handle the message within its rpc timeout,
+///   and replies its error if any
+/// - Replies 400 for a version other than `v{n}`, and 404 for a service without instances
+#[debug_handler]
+pub async fn handler_push(
+    State(node): State<Arc<Node>>,
+    Path((service, version, query)): Path<(String, String, String)>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    user: Option<Extension<AuthUser>>,
+    RawQuery(query_string): RawQuery,
+    headers: HeaderMap,
+    body: Bytes
+) -> Result<StatusCode, types::Error> {
+    check_path(&node, &service, &version)?;
+    let req = types::ClusterRequest {
+        zid: node.zid(),
+        version,
+        query,
+        payload: body.to_vec(),
+        user_id: user.map(|Extension(AuthUser(v))| v),
+        client_ip: Some(client_ip(&headers, addr)),
+        method: Method::POST.to_string(),
+        query_string: query_string.unwrap_or_default(),
+        trace_id: trace_id(&headers),
+        idempotency_key: idempotency_key(&headers),
+        ..Default::default()
+    };
+    if headers.contains_key(PUSH_ACK_HEADER) {
+        node.push_ack(&service, &req, node.timeout_for(&service)).await?;
+    } else {
+        node.push(&service, &req).await?;
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Interval of the comments sent on idle event streams, so proxies don't close them
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Forwards the request as a streaming rpc and relays every reply as a server-sent event
+/// - The payload of each response is sent as the `data` of an event
+/// - An error is sent as an `error` event with the JSON encoded `types::Error`, and ends the stream
+/// - The stream ends when the rpc completes
+/// - Replies 400 for a version other than `v{n}`, and 404 for a service without instances, before streaming
+#[debug_handler]
+pub async fn handler_sse(
+    State(node): State<Arc<Node>>,
+    Path((service, version, query)): Path<(String, String, String)>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    user: Option<Extension<AuthUser>>,
+    RawQuery(query_string): RawQuery,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, types::Error> {
+    check_path(&node, &service, &version)?;
+    let req = types::ClusterRequest {
+        zid: node.zid(),
+        version,
+        query,
+        user_id: user.map(|Extension(AuthUser(v))| v),
+        client_ip: Some(client_ip(&headers, addr)),
+        method: Method::GET.to_string(),
+        query_string: query_string.unwrap_or_default(),
+        trace_id: trace_id(&headers),
+        ..Default::default()
+    };
+    let replies = node.rpc_stream(&service, &req).await.map(|reply| {
+        Ok(match reply {
+            Ok(response) => Event::default().data(String::from_utf8_lossy(&response.payload.unwrap_or_default())),
+            Err(e) => Event::default().event("error").data(serde_json::to_string(&e).unwrap_or_default()),
+        })
+    });
+    Ok(Sse::new(replies).keep_alive(KeepAlive::new().interval(SSE_KEEP_ALIVE)))
+}
+
 #[debug_handler]
 pub async fn handler_websocket(
-    State(state): State<Arc<Node>>,
+    State(node): State<Arc<Node>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    user: Option<Extension<AuthUser>>,
+    headers: HeaderMap,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(state, socket))
+    let caller = Caller {
+        user_id: user.map(|Extension(AuthUser(v))| v),
+        client_ip: client_ip(&headers, addr),
+        trace_id: trace_id(&headers),
+    };
+    ws.on_upgrade(move |socket| handle_socket(node, caller, socket))
+}
+
+/// Identity of the client of a websocket, resolved once at the upgrade
+pub(crate) struct Caller {
+    pub(crate) user_id: Option<String>,
+    pub(crate) client_ip: String,
+    // trace id of the upgrade request, shared by the rpcs of the socket
+    pub(crate) trace_id: String,
+}
+
+/// Envelope of a request sent over the websocket as a bitcode encoded binary frame
+#[derive(Debug, bitcode::Encode, bitcode::Decode)]
+pub struct WsRequest {
+    pub service: String,
+    pub version: String,
+    pub query: String,
+    pub payload: Vec<u8>,
+}
+
+/// Most push subscriptions a websocket holds at once
+const WS_MAX_SUBSCRIPTIONS: usize = 32;
+
+/// Services whose pushes every websocket gets, read once from `WS_PUBLIC_SERVICES`
+static WS_PUBLIC_SERVICES: LazyLock<Vec<String>> = LazyLock::new(utils::vars::get_ws_public_services);
+
+/// Control message sent over the websocket as a JSON text frame, e.g. `{"op":"subscribe","id":1,"service":"chat","query":"room-1"}`
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum WsControl {
+    /// Relays the messages pushed to the service, only those of the query unless it is empty,
+    /// and only those of the user of the socket unless the service is one of `WS_PUBLIC_SERVICES`.
+    /// Subscribing again with the same id replaces the subscription
+    Subscribe {
+        id: u32,
+        service: String,
+        #[serde(default)]
+        query: String,
+    },
+    Unsubscribe {
+        id: u32,
+    },
 }
 
-async fn handle_socket(state: Arc<Node>, socket: WebSocket) {
-    
-}
\ No newline at end of file
+/// Message pushed to a subscription, relayed as a JSON text frame
+#[derive(Debug, serde::Serialize)]
+pub struct WsPush {
+    pub subscription: u32,
+    pub query: String,
+    pub data: String,
+}
+
+/// Serves requests over the websocket until the client closes it
+/// - Binary frames are decoded as `WsRequest` and answered with the response payload as a binary frame,
+///   errors are answered with the JSON encoded `types::Error` as a text frame
+/// - Text `ping` is answered with `pong`, ping frames with pong frames
+/// - Other text frames are `WsControl` messages managing push subscriptions, whose messages are sent as `WsPush`,
+///   at most `WS_MAX_SUBSCRIPTIONS` of them, failures are answered with the JSON encoded `types::Error`
+/// - Pushed messages wait in a buffer of `WS_BUFFER_SIZE` messages, so a slow client never holds up the subscriptions,
+///   once full the oldest message is dropped or the socket closed, per `WS_OVERFLOW_POLICY`
+async fn handle_socket(node: Arc<Node>, caller: Caller, mut socket: WebSocket) {
+    let pushes = Arc::new(Outbox::new(utils::vars::get_ws_buffer_size(), utils::vars::get_ws_overflow_policy()));
+    // dropping the sender of a subscription stops its relay, so they all stop with the socket
+    let mut subscriptions: HashMap<u32, oneshot::Sender<()>> = HashMap::new();
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(v)) => v,
+                Some(Err(e)) => {
+                    tracing::debug!("{}:{} {}", file!(), line!(), e);
+                    break;
+                }
+                None => break,
+            },
+            push = pushes.pop() => match push {
+                Some(push) => {
+                    if let Err(e) = socket.send(push).await {
+                        tracing::debug!("{}:{} {}", file!(), line!(), e);
+                        break;
+                    }
+                    continue;
+                }
+                None => {
+                    tracing::warn!("[gateway] websocket of {} closed, too slow to keep up with its pushes", caller.client_ip);
+                    let close = CloseFrame { code: close_code::AGAIN, reason: "too slow".into() };
+                    if let Err(e) = socket.send(Message::Close(Some(close))).await {
+                        tracing::debug!("{}:{} {}", file!(), line!(), e);
+                    }
+                    break;
+                }
+            },
+        };
+        let reply = match message {
+            Message::Binary(bytes) => Some(ws_rpc(&node, &caller, &bytes).await),
+            Message::Text(text) if text.as_str() == "ping" => Some(Message::Text("pong".into())),
+            Message::Text(text) => ws_control(&node, &caller, &mut subscriptions, &pushes, text.as_str()).await,
+            Message::Ping(data) => Some(Message::Pong(data)),
+            Message::Close(_) => break,
+            _ => None,
+        };
+        if let Some(reply) = reply && let Err(e) = socket.send(reply).await {
+            tracing::debug!("{}:{} {}", file!(), line!(), e);
+            break;
+        }
+    }
+}
+
+pub(crate) async fn ws_rpc(node: &Node, caller: &Caller, bytes: &[u8]) -> Message {
+    let result = match bitcode::decode::<WsRequest>(bytes) {
+        Ok(request) => ws_forward(node, caller, request).await,
+        Err(e) => {
+            tracing::debug!("{}:{} {}", file!(), line!(), e);
+            Err(types::ERROR_CODE_DESERIALIZE.into())
+        }
+    };
+    match result {
+        Ok(response) => Message::Binary(response.payload.unwrap_or_default().into()),
+        Err(e) => Message::Text(serde_json::to_string(&e).unwrap_or_default().into()),
+    }
+}
+
+/// Forwards the request as an rpc to the service, refusing malformed versions and unknown services like `handler_gateway`
+async fn ws_forward(node: &Node, caller: &Caller, request: WsRequest) -> types::Result<types::ClusterResponse> {
+    check_path(node, &request.service, &request.version)?;
+    let req = types::ClusterRequest {
+        zid: node.zid(),
+        version: request.version,
+        query: request.query,
+        payload: request.payload,
+        user_id: caller.user_id.clone(),
+        client_ip: Some(caller.client_ip.clone()),
+        trace_id: caller.trace_id.clone(),
+        ..Default::default()
+    };
+    node.rpc(&request.service, &req).await
+}
+
+/// Applies a control message to the subscriptions of the websocket, returns the error to send back if any
+async fn ws_control(
+    node: &Node,
+    caller: &Caller,
+    subscriptions: &mut HashMap<u32, oneshot::Sender<()>>,
+    pushes: &Arc<Outbox<Message>>,
+    text: &str,
+) -> Option<Message> {
+    let result = match serde_json::from_str::<WsControl>(text) {
+        Ok(WsControl::Subscribe { id, service, query }) => {
+            if !subscriptions.contains_key(&id) && subscriptions.len() >= WS_MAX_SUBSCRIPTIONS {
+                Err(types::ERROR_CODE_TOO_MANY_REQUESTS.into())
+            } else {
+                let subscriber = if WS_PUBLIC_SERVICES.contains(&service) {
+                    Some(node.subscribe_pushes(&service).await)
+                } else if let Some(user_id) = &caller.user_id {
+                    Some(node.subscribe_user_pushes(&service, user_id).await)
+                } else {
+                    None
+                };
+                match subscriber {
+                    None => Err(types::ERROR_CODE_UNAUTHORIZED.into()),
+                    Some(Ok(subscriber)) => {
+                        let (stop, stopped) = oneshot::channel();
+                        tokio::spawn(ws_relay(subscriber, id, query, pushes.clone(), stopped));
+                        subscriptions.insert(id, stop);
+                        Ok(())
+                    }
+                    Some(Err(e)) => {
+                        tracing::error!("{}:{} {}", file!(), line!(), e);
+                        Err(types::ERROR_CODE_INTERNAL_ERROR.into())
+                    }
+                }
+            }
+        }
+        Ok(WsControl::Unsubscribe { id }) => {
+            subscriptions.remove(&id);
+            Ok(())
+        }
+        Err(e) => {
+            tracing::debug!("{}:{} {}", file!(), line!(), e);
+            Err(types::ERROR_CODE_DESERIALIZE.into())
+        }
+    };
+    result.err().map(|e: types::Error| Message::Text(serde_json::to_string(&e).unwrap_or_default().into()))
+}
+
+/// Relays the messages of the subscription to the websocket until it is stopped or the socket closes
+async fn ws_relay(
+    subscriber: cluster::subscription::PushSubscriber,
+    id: u32,
+    query: String,
+    pushes: Arc<Outbox<Message>>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    loop {
+        let request = tokio::select! {
+            _ = &mut stopped => break,
+            request = subscriber.recv() => match request {
+                Some(v) => v,
+                None => break,
+            },
+        };
+        if !query.is_empty() && request.query != query {
+            continue;
+        }
+        let push = WsPush {
+            subscription: id,
+            query: request.query,
+            data: String::from_utf8_lossy(&request.payload).into_owned(),
+        };
+        let text = serde_json::to_string(&push).unwrap_or_default();
+        if pushes.push(Message::Text(text.into())).is_err() {
+            break;
+        }
+    }
+    if let Err(e) = subscriber.undeclare().await {
+        tracing::debug!("{}:{} {}", file!(), line!(), e);
+    }
+}
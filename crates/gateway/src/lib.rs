@@ -2,23 +2,27 @@ mod gateway;
 mod security;
 mod context;
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{future::IntoFuture, net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{
-    http::{header, HeaderName, HeaderValue, Method}, routing::{any, get}, Json, Router
+    extract::State, http::{header, HeaderName, HeaderValue, Method, StatusCode}, routing::{any, get, post}, Json, Router
 };
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use traits::gateway::GatewayTraitRpcWrapper;
 
 use crate::{
-    gateway::{handler_gateway, handler_websocket, GatewaytHandler},
-    security::middleware::security_headers_middleware, context::AppContext,
+    gateway::{handler_gateway, handler_push, handler_sse, handler_websocket, trace_id_middleware, GatewaytHandler},
+    security::middleware::{jwt_auth_middleware, rate_limit_middleware, security_headers_middleware}, context::AppContext,
 };
 
 pub const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
 pub const REAL_IP_HEADER: &str = "x-real-ip";
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+pub const PUSH_ACK_HEADER: &str = "x-push-ack";
 
 
+/// Liveness probe, healthy as long as the gateway serves requests
 async fn api_health_check() -> axum::Json<serde_json::Value> {
     axum::Json(serde_json::json!({
         "status": "healthy",
@@ -26,6 +30,24 @@ async fn api_health_check() -> axum::Json<serde_json::Value> {
     }))
 }
 
+/// Readiness probe, 503 until the node bootstrapped its registry and has an instance of each `SERVER_REQUIRED_SERVICES`
+async fn api_ready_check(State(node): State<Arc<gateway::Node>>) -> (StatusCode, Json<serde_json::Value>) {
+    let missing: Vec<String> = utils::vars::get_required_services()
+        .into_iter()
+        .filter(|service| node.instances(service).is_empty())
+        .collect();
+    let (status, code) = if node.is_bootstrapped() && missing.is_empty() {
+        ("ready", StatusCode::OK)
+    } else {
+        ("not_ready", StatusCode::SERVICE_UNAVAILABLE)
+    };
+    (code, Json(serde_json::json!({
+        "status": status,
+        "missing": missing,
+        "timestamp": chrono::Utc::now().to_rfc3339()
+    })))
+}
+
 async fn api_versions() -> Json<serde_json::Value> {
     Json(serde_json::json!({
         "versions": {
@@ -38,18 +60,118 @@ async fn api_versions() -> Json<serde_json::Value> {
     }))
 }
 
-pub async fn start() {
+/// Installs the prometheus recorder of the rpc metrics and serves them on `/metrics`
+#[cfg(feature = "metrics")]
+fn metrics_routes<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    let handle = match metrics_exporter_prometheus::PrometheusBuilder::new().install_recorder() {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+            return Router::new();
+        }
+    };
+    Router::new().route(security::config::METRICS_PATH, get(move || std::future::ready(handle.render())))
+}
+
+#[cfg(not(feature = "metrics"))]
+fn metrics_routes<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new()
+}
+
+/// Error that stops the gateway
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error(transparent)]
+    Session(#[from] utils::zenoh_zession::SessionError),
+    #[error("failed to start the cluster node: {0}")]
+    Node(utils::zenoh::Error),
+    #[error("failed to bind {0}: {1}")]
+    Bind(String, #[source] std::io::Error),
+    #[error("server error: {0}")]
+    Serve(#[source] std::io::Error),
+    #[error("JWT_SECRET is not set, every request outside the public paths would be rejected")]
+    MissingSecret,
+}
+
+impl StartError {
+    /// Exit code of the process stopped by the error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartError::Session(_) | StartError::Node(_) => utils::EXIT_START_NODE_ERROR,
+            StartError::Bind(..) => utils::EXIT_BIND_ERROR,
+            StartError::Serve(_) => utils::EXIT_SERVE_ERROR,
+            StartError::MissingSecret => utils::EXIT_CONFIG_ERROR,
+        }
+    }
+}
+
+/// Methods allowed by CORS, the defaults merged with `SERVER_ALLOW_METHODS`
+fn allow_methods() -> Vec<Method> {
+    let mut methods = vec![Method::GET, Method::POST, Method::PATCH, Method::DELETE, Method::OPTIONS];
+    for method in utils::vars::get_allow_methods() {
+        match Method::from_bytes(method.to_ascii_uppercase().as_bytes()) {
+            Ok(v) if !methods.contains(&v) => methods.push(v),
+            Ok(_) => {}
+            Err(_) => tracing::warn!("{}:{} invalid method {method} in SERVER_ALLOW_METHODS", file!(), line!()),
+        }
+    }
+    methods
+}
+
+/// Headers allowed by CORS, the defaults merged with `SERVER_ALLOW_HEADERS`
+fn allow_headers() -> Vec<HeaderName> {
+    let mut headers = vec![
+        header::AUTHORIZATION,
+        header::ACCEPT,
+        header::CONTENT_TYPE,
+        header::UPGRADE,
+        header::HOST,
+        header::CONNECTION,
+        header::ORIGIN,
+        header::SEC_WEBSOCKET_KEY,
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderName::from_static(REAL_IP_HEADER),
+        HeaderName::from_static(FORWARDED_FOR_HEADER),
+        HeaderName::from_static(REQUEST_ID_HEADER),
+        HeaderName::from_static(IDEMPOTENCY_KEY_HEADER),
+        HeaderName::from_static(PUSH_ACK_HEADER),
+    ];
+    for name in utils::vars::get_allow_headers() {
+        match HeaderName::from_bytes(name.as_bytes()) {
+            Ok(v) if !headers.contains(&v) => headers.push(v),
+            Ok(_) => {}
+            Err(_) => tracing::warn!("{}:{} invalid header {name} in SERVER_ALLOW_HEADERS", file!(), line!()),
+        }
+    }
+    headers
+}
+
+/// Runs the gateway until the shutdown signal, exits the process with the code of the error it fails with
+pub async fn start_or_exit() {
+    if let Err(e) = start().await {
+        tracing::error!("{}:{} {}", file!(), line!(), e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+/// Runs the gateway until the shutdown signal
+/// Returns the error of a missing `JWT_SECRET`, of creating the session or the node, binding `SERVER_BIND` or serving
+pub async fn start() -> Result<(), StartError> {
     utils::setup_env();
-    
-    let ctx = Arc::new(AppContext::new().await);
+    if security::middleware::AUTH_CONFIG.secret.is_empty() {
+        return Err(StartError::MissingSecret);
+    }
+
+    let ctx = Arc::new(AppContext::try_new().await?);
 
     let trace_layer = tower_http::trace::TraceLayer::new_for_http()
         .make_span_with(|request: &axum::http::Request<_>| {
             tracing::info_span!(
                 "request",
                 method = %request.method(),
-                uri = %request.uri(),
-                trace_id = %utils::xid::new(),
+                // the query may carry the `access_token` of a websocket or an event stream
+                path = %request.uri().path(),
+                trace_id = %gateway::trace_id(request.headers()),
             )
         })
         .on_response(
@@ -76,57 +198,135 @@ pub async fn start() {
                     )
                 })
             })
-            .allow_methods([
-                Method::GET,
-                Method::POST,
-                Method::PATCH,
-                Method::DELETE,
-                Method::OPTIONS,
-            ])
+            .allow_methods(allow_methods())
             .allow_credentials(!origins.contains(&"*".to_string()))
-            .allow_headers([
-                header::AUTHORIZATION,
-                header::ACCEPT,
-                header::CONTENT_TYPE,
-                header::UPGRADE,
-                header::HOST,
-                header::CONNECTION,
-                header::ORIGIN,
-                header::SEC_WEBSOCKET_KEY,
-                header::SEC_WEBSOCKET_PROTOCOL,
-                HeaderName::from_static(REAL_IP_HEADER),
-                HeaderName::from_static(FORWARDED_FOR_HEADER),
-            ]);       
+            .allow_headers(allow_headers());
 
     // start cluster node
     let node = {
         let ctx = ctx.clone();
-        Arc::new(cluster::Node::new(ctx, GatewayTraitRpcWrapper(GatewaytHandler)).await)
+        let node = cluster::Node::try_new(ctx, GatewayTraitRpcWrapper(GatewaytHandler)).await.map_err(StartError::Node)?;
+        Arc::new(node)
     };
 
+    let limiter = Arc::new(utils::rate_limit::RateLimiter::new(
+        utils::vars::get_rate_limit_capacity(),
+        utils::vars::get_rate_limit_refill(),
+    ));
+
     let app = Router::new()
         // Redirect root path to latest version docs or return version info
         .route("/health", any(api_health_check))
+        .route("/ready", any(api_ready_check))
         .route("/ws", any(handler_websocket))
+        .route("/{service}/{version}/sse/{*params}", get(handler_sse))
+        .route("/{service}/{version}/push/{*params}", post(handler_push))
         .route("/{service}/{version}/{*params}", any(handler_gateway))
         .route("/", get(api_versions))
-        .with_state(node)
+        .merge(metrics_routes())
+        .with_state(node.clone())
+        .layer(axum::middleware::from_fn(jwt_auth_middleware))
+        .layer(axum::middleware::from_fn_with_state(limiter, rate_limit_middleware))
         .layer(trace_layer)
+        .layer(axum::middleware::from_fn(trace_id_middleware))
         .layer(cors_layer)
         .layer(axum::middleware::from_fn(security_headers_middleware))
         .layer(tower_http::catch_panic::CatchPanicLayer::new());
 
-    let listener = tokio::net::TcpListener::bind(&utils::vars::get_server_bind())
+    let bind = utils::vars::get_server_bind();
+    let listener = tokio::net::TcpListener::bind(&bind)
         .await
-        .unwrap();
+        .map_err(|e| StartError::Bind(bind, e))?;
 
+    // once signaled, the gateway stops accepting connections and its node stops being announced,
+    // the requests in flight get up to `SERVER_SHUTDOWN_TIMEOUT_SECONDS` before they are closed
+    let timeout = Duration::from_secs(utils::vars::get_shutdown_timeout());
+    let (stopping, stopped) = tokio::sync::oneshot::channel();
+    let signal = async move {
+        utils::shutdown_signal().await;
+        tracing::info!("[gateway] shutting down, waiting up to {}s for the requests in flight", timeout.as_secs());
+        tokio::spawn(async move { node.drain(timeout).await });
+        let _ = stopping.send(());
+    };
     let graceful = axum::serve(
             listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
         )
-        .with_graceful_shutdown(utils::shutdown_signal());
-    
-    if let Err(e) = graceful.await {
-        tracing::error!("{}:{} server error: {:?}", file!(), line!(), e);
+        .with_graceful_shutdown(signal);
+
+    tokio::select! {
+        result = graceful.into_future() => result.map_err(StartError::Serve),
+        _ = async {
+            if stopped.await.is_err() {
+                std::future::pending::<()>().await;
+            }
+            tokio::time::sleep(timeout).await;
+        } => {
+            tracing::warn!("{}:{} requests still in flight after {}s, closing them", file!(), line!(), timeout.as_secs());
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::{ConnectInfo, Request}};
+    use tower::ServiceExt;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_check_path() {
+        let ctx = Arc::new(AppContext::try_new().await.unwrap());
+        let node = Arc::new(cluster::Node::try_new(ctx, GatewayTraitRpcWrapper(GatewaytHandler)).await.unwrap());
+        assert!(node.wait_ready(&["gateway"], Duration::from_secs(5)).await);
+        let app = Router::new()
+            .route("/{service}/{version}/sse/{*params}", get(handler_sse))
+            .route("/{service}/{version}/push/{*params}", post(handler_push))
+            .route("/{service}/{version}/{*params}", any(handler_gateway))
+            .with_state(node.clone());
+        let status = |method: Method, uri: &str| {
+            let mut request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        // malformed versions and unknown services are refused before reaching the cluster, whatever the route
+        for (method, route) in [(Method::GET, "sse/feed"), (Method::POST, "push/feed"), (Method::GET, "feed")] {
+            assert_eq!(status(method.clone(), &format!("/gateway/banana/{route}")).await, StatusCode::BAD_REQUEST);
+            assert_eq!(status(method.clone(), &format!("/gateway/v/{route}")).await, StatusCode::BAD_REQUEST);
+            assert_eq!(status(method, &format!("/check_path_unknown/v1/{route}")).await, StatusCode::NOT_FOUND);
+        }
+        assert_eq!(status(Method::GET, "/gateway/v1/sse/feed").await, StatusCode::OK);
+
+        // and over the websocket, whose errors are sent back as text frames
+        let caller = gateway::Caller { user_id: None, client_ip: "127.0.0.1".to_string(), trace_id: "trace".to_string() };
+        for (service, version, code) in [
+            ("gateway", "banana", types::ERROR_CODE_INVALID_VERSION.0),
+            ("gateway", "v", types::ERROR_CODE_INVALID_VERSION.0),
+            ("check_path_unknown", "v1", types::ERROR_CODE_SERVICE_NOT_FOUND.0),
+        ] {
+            let request = gateway::WsRequest { service: service.to_string(), version: version.to_string(), query: "feed".to_string(), payload: Vec::new() };
+            let axum::extract::ws::Message::Text(text) = gateway::ws_rpc(&node, &caller, &bitcode::encode(&request)).await else {
+                panic!("{service}/{version} not refused");
+            };
+            assert_eq!(serde_json::from_str::<types::Error>(text.as_str()).unwrap().code, code);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_metrics_public() {
+        use axum::middleware::Next;
+        use security::{config::AuthConfig, middleware::configurable_jwt_auth};
+
+        let config = Arc::new(AuthConfig { secret: "secret".to_string(), ..AuthConfig::default() });
+        let app: Router = metrics_routes().layer(axum::middleware::from_fn(move |request: Request, next: Next| {
+            let config = config.clone();
+            async move { configurable_jwt_auth(request, next, &config).await }
+        }));
+        // prometheus scrapes without a token
+        let request = Request::get(security::config::METRICS_PATH).body(Body::empty()).unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::OK);
     }
 }
@@ -1,4 +1,4 @@
 #[tokio::main]
 async fn main() {
-    gateway::start().await;
+    gateway::start_or_exit().await;
 }
\ No newline at end of file
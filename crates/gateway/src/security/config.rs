@@ -1,5 +1,5 @@
 // src/security/config.rs
-use std::time::Duration;
+use std::{net::IpAddr, time::Duration};
 
 #[derive(Debug, Clone)]
 pub struct SecurityHeadersConfig {
@@ -11,6 +11,11 @@ pub struct SecurityHeadersConfig {
     pub hsts_max_age: Duration,
     pub enable_permissions_policy: bool,
     pub permissions_policy: String,
+    pub enable_frame_options: bool,
+    pub enable_content_type_options: bool,
+    pub enable_referrer_policy: bool,
+    // header of the trusted proxy telling the scheme of the client request, HSTS is only sent over HTTPS
+    pub proto_header: String,
 }
 
 #[derive(Debug, Clone)]
@@ -30,6 +35,10 @@ impl Default for SecurityHeadersConfig {
             hsts_max_age: Duration::from_secs(31536000), // 1 year
             enable_permissions_policy: true,
             permissions_policy: default_permissions_policy(),
+            enable_frame_options: true,
+            enable_content_type_options: true,
+            enable_referrer_policy: true,
+            proto_header: utils::vars::get_security_proto_header(),
         }
     }
 }
@@ -38,13 +47,55 @@ impl Default for SecurityHeadersConfig {
 pub fn production_security_config() -> SecurityHeadersConfig {
     SecurityHeadersConfig {
         enable_csp: true,
-        enable_hsts: true, // Note: Enable HSTS only when using HTTPS
+        enable_hsts: true, // only sent to requests made over HTTPS
         enable_xss_protection: true,
         frame_options: FrameOptions::Deny,
         csp_directives: production_csp_directives(),
         hsts_max_age: Duration::from_secs(63072000), // 2 years
         enable_permissions_policy: true,
         permissions_policy: production_permissions_policy(),
+        enable_frame_options: true,
+        enable_content_type_options: true,
+        enable_referrer_policy: true,
+        proto_header: utils::vars::get_security_proto_header(),
+    }
+}
+
+impl SecurityHeadersConfig {
+    /// Config of the `SECURITY_PROFILE` preset, `production` by default,
+    /// without the headers named in `SECURITY_DISABLE_HEADERS`
+    pub fn from_env() -> Self {
+        let profile = utils::vars::get_security_profile();
+        let mut config = match profile.to_ascii_lowercase().as_str() {
+            "default" => Self::default(),
+            "production" => production_security_config(),
+            _ => {
+                tracing::warn!("{}:{} invalid SECURITY_PROFILE {profile}, using production", file!(), line!());
+                production_security_config()
+            }
+        };
+        for header in utils::vars::get_security_disable_headers() {
+            if !config.disable(&header) {
+                tracing::warn!("{}:{} unknown header {header} in SECURITY_DISABLE_HEADERS", file!(), line!());
+            }
+        }
+        config
+    }
+
+    /// Stops sending the security header of the name, false for a name that isn't one of them
+    pub fn disable(&mut self, header: &str) -> bool {
+        let enabled = match header.to_ascii_lowercase().as_str() {
+            "content-security-policy" => &mut self.enable_csp,
+            "strict-transport-security" => &mut self.enable_hsts,
+            "x-xss-protection" => &mut self.enable_xss_protection,
+            "x-frame-options" => &mut self.enable_frame_options,
+            "x-content-type-options" => &mut self.enable_content_type_options,
+            "referrer-policy" => &mut self.enable_referrer_policy,
+            "permissions-policy" => &mut self.enable_permissions_policy,
+            _ => return false,
+        };
+        *enabled = false;
+        true
     }
 }
 
@@ -110,4 +161,83 @@ fn production_permissions_policy() -> String {
         "gyroscope=()",
         "accelerometer=()",
     ].join(", ")
-}
\ No newline at end of file
+}
+
+/// Paths served without a bearer token, matched exactly against the request path
+pub const PUBLIC_PATHS: [&str; 3] = ["/", "/health", "/ready"];
+
+/// Path of the prometheus metrics, public with the `metrics` feature so scrapers need no token
+pub const METRICS_PATH: &str = "/metrics";
+
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub secret: String,
+    pub public_paths: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        let mut public_paths: Vec<String> = PUBLIC_PATHS.iter().map(|v| v.to_string()).collect();
+        if cfg!(feature = "metrics") {
+            public_paths.push(METRICS_PATH.to_string());
+        }
+        Self {
+            secret: utils::vars::get_jwt_secret(),
+            public_paths,
+        }
+    }
+}
+
+/// Proxies trusted to set `X-Forwarded-For` and `X-Real-IP`, from `SERVER_TRUSTED_PROXIES`,
+/// the headers of the requests made by any other peer are ignored, clients could forge them
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    ranges: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    pub fn from_env() -> Self {
+        let proxies = utils::vars::get_trusted_proxies();
+        Self::parse(proxies.iter().map(|v| v.as_str()))
+    }
+
+    /// Ranges of the addresses, e.g. `10.0.0.1` or `10.0.0.0/8`, invalid ones are skipped with a warning
+    pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> Self {
+        let ranges = values.into_iter()
+            .filter_map(|value| {
+                let range = parse_range(value);
+                if range.is_none() {
+                    tracing::warn!("{}:{} invalid proxy {value} in SERVER_TRUSTED_PROXIES", file!(), line!());
+                }
+                range
+            })
+            .collect();
+        Self { ranges }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.ranges.iter().any(|(net, len)| match (net, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix(net.to_bits() as u128, ip.to_bits() as u128, 32, *len),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix(net.to_bits(), ip.to_bits(), 128, *len),
+            _ => false,
+        })
+    }
+}
+
+fn parse_range(value: &str) -> Option<(IpAddr, u8)> {
+    let (ip, len) = match value.split_once('/') {
+        Some((ip, len)) => (ip.parse::<IpAddr>().ok()?.to_canonical(), Some(len.parse::<u8>().ok()?)),
+        None => (value.parse::<IpAddr>().ok()?.to_canonical(), None),
+    };
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    match len {
+        Some(len) if len > max => None,
+        len => Some((ip, len.unwrap_or(max))),
+    }
+}
+
+/// Whether the first `len` of the `bits` bits of the addresses are the same
+fn prefix(net: u128, ip: u128, bits: u8, len: u8) -> bool {
+    len == 0 || (net ^ ip) >> (bits - len) == 0
+}
@@ -1,15 +1,34 @@
 // src/security/middleware.rs
+use std::{net::SocketAddr, sync::{Arc, LazyLock}};
+
 use axum::{
-    extract::Request, http::HeaderValue, middleware::Next, response::Response
+    extract::{ConnectInfo, Request, State}, http::{header, HeaderValue}, middleware::Next, response::{IntoResponse, Response}
 };
-use super::config::SecurityHeadersConfig;
+use utils::rate_limit::RateLimiter;
+use super::config::{AuthConfig, SecurityHeadersConfig, TrustedProxies};
+
+/// Subject of the verified bearer token, inserted into the request extensions by `jwt_auth_middleware`
+#[derive(Debug, Clone)]
+pub struct AuthUser(pub String);
+
+/// Query parameter and cookie carrying the token of the websocket and SSE requests,
+/// browsers can't set the `Authorization` header of a `WebSocket` or an `EventSource`
+pub const ACCESS_TOKEN: &str = "access_token";
+
+/// Auth config of the gateway, `JWT_SECRET` read once
+pub(crate) static AUTH_CONFIG: LazyLock<AuthConfig> = LazyLock::new(AuthConfig::default);
+
+/// Proxies of the gateway, `SERVER_TRUSTED_PROXIES` read once
+pub(crate) static TRUSTED_PROXIES: LazyLock<TrustedProxies> = LazyLock::new(TrustedProxies::from_env);
+
+/// Security headers of the gateway, read once from `SECURITY_PROFILE` and `SECURITY_DISABLE_HEADERS`
+static SECURITY_HEADERS: LazyLock<SecurityHeadersConfig> = LazyLock::new(SecurityHeadersConfig::from_env);
 
 pub async fn security_headers_middleware(
     request: Request,
     next: Next,
 ) -> Response {
-    let config = super::config::production_security_config();
-    configurable_security_headers(request, next, &config).await
+    configurable_security_headers(request, next, &SECURITY_HEADERS).await
 }
 
 pub async fn configurable_security_headers(
@@ -17,19 +36,35 @@ pub async fn configurable_security_headers(
     next: Next,
     config: &SecurityHeadersConfig,
 ) -> Response {
+    let https = is_https(&request, config, &TRUSTED_PROXIES);
     let mut response = next.run(request).await;
-    add_security_headers(response.headers_mut(), config);
+    add_security_headers(response.headers_mut(), config, https);
     response
 }
 
-fn add_security_headers(headers: &mut axum::http::HeaderMap, config: &SecurityHeadersConfig) {
+/// Whether the client made the request over HTTPS, as told by the proxy header of the config when the peer is a trusted proxy,
+/// or by the scheme of the request when served over TLS directly
+fn is_https(request: &Request, config: &SecurityHeadersConfig, proxies: &TrustedProxies) -> bool {
+    let trusted = request.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .is_some_and(|ConnectInfo(addr)| proxies.contains(addr.ip()));
+    request.headers()
+        .get(config.proto_header.as_str())
+        .filter(|_| trusted)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().eq_ignore_ascii_case("https"))
+        .unwrap_or_else(|| request.uri().scheme_str() == Some("https"))
+}
+
+fn add_security_headers(headers: &mut axum::http::HeaderMap, config: &SecurityHeadersConfig, https: bool) {
     // 1. Content Security Policy
     if config.enable_csp &&  let Ok(header_value) = HeaderValue::from_str(&config.csp_directives) {
         headers.insert("content-security-policy", header_value);
     }
 
-    // 2. Strict Transport Security (仅在 HTTPS 时启用)
-    if config.enable_hsts {
+    // 2. Strict Transport Security, only over HTTPS, browsers would keep refusing plain HTTP otherwise
+    if config.enable_hsts && https {
         let hsts_value = format!(
             "max-age={}; includeSubDomains{}",
             config.hsts_max_age.as_secs(),
@@ -41,14 +76,18 @@ fn add_security_headers(headers: &mut axum::http::HeaderMap, config: &SecurityHe
     }
 
     // 3. X-Content-Type-Options
-    headers.insert("x-content-type-options", HeaderValue::from_static("nosniff"));
+    if config.enable_content_type_options {
+        headers.insert("x-content-type-options", HeaderValue::from_static("nosniff"));
+    }
 
     // 4. X-Frame-Options
-    let frame_options_value = match &config.frame_options {
-        super::config::FrameOptions::Deny => "DENY",
-        super::config::FrameOptions::SameOrigin => "SAMEORIGIN",
-    };
-    headers.insert("x-frame-options", HeaderValue::from_static(frame_options_value));
+    if config.enable_frame_options {
+        let frame_options_value = match &config.frame_options {
+            super::config::FrameOptions::Deny => "DENY",
+            super::config::FrameOptions::SameOrigin => "SAMEORIGIN",
+        };
+        headers.insert("x-frame-options", HeaderValue::from_static(frame_options_value));
+    }
 
     // 5. X-XSS-Protection
     if config.enable_xss_protection {
@@ -56,7 +95,9 @@ fn add_security_headers(headers: &mut axum::http::HeaderMap, config: &SecurityHe
     }
 
     // 6. Referrer-Policy
-    headers.insert("referrer-policy", HeaderValue::from_static("strict-origin-when-cross-origin"));
+    if config.enable_referrer_policy {
+        headers.insert("referrer-policy", HeaderValue::from_static("strict-origin-when-cross-origin"));
+    }
 
     // 7. Permissions-Policy
     if config.enable_permissions_policy && let Ok(header_value) = HeaderValue::from_str(&config.permissions_policy) {
@@ -72,4 +113,206 @@ fn remove_sensitive_headers(headers: &mut axum::http::HeaderMap) {
     headers.remove("x-powered-by");
     headers.remove("x-aspnet-version");
     headers.remove("x-aspnetmvc-version");
-}
\ No newline at end of file
+}
+
+pub async fn jwt_auth_middleware(
+    request: Request,
+    next: Next,
+) -> Response {
+    configurable_jwt_auth(request, next, &AUTH_CONFIG).await
+}
+
+/// Whether the request opens a websocket or an event stream, whose token may come from the query or a cookie
+fn is_stream(request: &Request) -> bool {
+    let path = request.uri().path();
+    path == "/ws" || path.split('/').nth(3) == Some("sse")
+}
+
+/// Token of the request, from the `Authorization: Bearer` header,
+/// or the `access_token` query parameter or cookie of the websocket and SSE requests
+fn request_token(request: &Request) -> Option<&str> {
+    let bearer = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if bearer.is_some() || !is_stream(request) {
+        return bearer;
+    }
+    let query = request.uri().query().unwrap_or_default().split('&')
+        .find_map(|v| v.strip_prefix(ACCESS_TOKEN)?.strip_prefix('='));
+    query.or_else(|| {
+        request.headers()
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .find_map(|v| v.trim().strip_prefix(ACCESS_TOKEN)?.strip_prefix('='))
+    })
+}
+
+/// Rejects requests without a valid token with 401, see `request_token`,
+/// requests to the public paths are passed through untouched
+pub async fn configurable_jwt_auth(
+    mut request: Request,
+    next: Next,
+    config: &AuthConfig,
+) -> Response {
+    if config.public_paths.iter().any(|v| v == request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let subject = request_token(&request)
+        .filter(|_| !config.secret.is_empty())
+        .and_then(|token| utils::jwt::verify_token(token.trim(), config.secret.as_bytes()));
+
+    match subject {
+        Some(subject) => {
+            request.extensions_mut().insert(AuthUser(subject));
+            next.run(request).await
+        },
+        None => types::Error::from(types::ERROR_CODE_UNAUTHORIZED).into_response(),
+    }
+}
+
+/// Throttles every client ip with its own token bucket,
+/// over limit requests get 429 with the seconds to wait in `Retry-After` and in the error data
+pub async fn rate_limit_middleware(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client_ip = crate::gateway::client_ip(request.headers(), addr);
+    match limiter.check(&client_ip) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil() as u64;
+            tracing::debug!("{}:{} rate limit exceeded by {client_ip}", file!(), line!());
+            let (code, message) = types::ERROR_CODE_TOO_MANY_REQUESTS;
+            types::Error::with_data(code, message, serde_json::json!({ "retry_after": retry_after }))
+                .with_retry_after(std::time::Duration::from_secs(retry_after))
+                .into_response()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Extension, Router};
+    use tower::ServiceExt;
+
+    /// Status and body of the request to a router replying the subject of the token
+    async fn auth(request: axum::http::Request<Body>) -> (u16, String) {
+        let config = Arc::new(AuthConfig { secret: "secret".to_string(), public_paths: vec!["/health".to_string()] });
+        let subject = |user: Option<Extension<AuthUser>>| async move { user.map(|Extension(AuthUser(v))| v).unwrap_or_default() };
+        let app = Router::new()
+            .route("/health", get(subject))
+            .route("/ws", get(subject))
+            .route("/{service}/{version}/sse/{*params}", get(subject))
+            .route("/{service}/{version}/{*params}", get(subject))
+            .layer(axum::middleware::from_fn(move |request: Request, next: Next| {
+                let config = config.clone();
+                async move { configurable_jwt_auth(request, next, &config).await }
+            }));
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status().as_u16();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    fn get_request(uri: &str, header: Option<(header::HeaderName, String)>) -> axum::http::Request<Body> {
+        let mut builder = axum::http::Request::get(uri);
+        if let Some((name, value)) = header {
+            builder = builder.header(name, value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_token() {
+        let token = utils::jwt::create_token("user-1", b"secret");
+        let bearer = Some((header::AUTHORIZATION, format!("Bearer {token}")));
+        let cookie = Some((header::COOKIE, format!("theme=dark; {ACCESS_TOKEN}={token}")));
+
+        assert_eq!(auth(get_request("/health", None)).await, (200, String::new()));
+        assert_eq!(auth(get_request("/orders/v1/list", bearer.clone())).await, (200, "user-1".to_string()));
+        assert_eq!(auth(get_request("/orders/v1/list", None)).await.0, 401);
+
+        // browsers can't set the header of a websocket or an event stream
+        assert_eq!(auth(get_request(&format!("/ws?{ACCESS_TOKEN}={token}"), None)).await, (200, "user-1".to_string()));
+        assert_eq!(auth(get_request("/ws", cookie.clone())).await, (200, "user-1".to_string()));
+        assert_eq!(auth(get_request(&format!("/orders/v1/sse/feed?a=1&{ACCESS_TOKEN}={token}"), None)).await, (200, "user-1".to_string()));
+        assert_eq!(auth(get_request("/orders/v1/sse/feed", cookie.clone())).await, (200, "user-1".to_string()));
+        assert_eq!(auth(get_request("/ws?access_token=invalid", None)).await.0, 401);
+        assert_eq!(auth(get_request("/ws", None)).await.0, 401);
+
+        // the other routes only take the header
+        assert_eq!(auth(get_request(&format!("/orders/v1/list?{ACCESS_TOKEN}={token}"), None)).await.0, 401);
+        assert_eq!(auth(get_request("/orders/v1/list", cookie)).await.0, 401);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_forwarded() {
+        let limiter = Arc::new(RateLimiter::new(2, 0.0));
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(axum::middleware::from_fn_with_state(limiter, rate_limit_middleware));
+        let request = |forwarded: String| {
+            let mut request = get_request("/", Some((header::HeaderName::from_static(crate::FORWARDED_FOR_HEADER), forwarded)));
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([203, 0, 113, 7], 4000))));
+            request
+        };
+
+        // the peer isn't a trusted proxy, forging a new X-Forwarded-For doesn't get it a new bucket,
+        // and without refill its wait saturates instead of panicking
+        let mut statuses = Vec::new();
+        for i in 0..3 {
+            statuses.push(app.clone().oneshot(request(format!("198.51.100.{i}"))).await.unwrap().status().as_u16());
+        }
+        assert_eq!(statuses, [200, 200, 429]);
+    }
+
+    #[test]
+    fn test_forwarded_ip() {
+        use crate::gateway::forwarded_ip;
+        let proxies = TrustedProxies::parse(["10.0.0.0/8", "2001:db8::1", "invalid", "10.0.0.0/33"]);
+        let headers = |forwarded: &str| {
+            let mut headers = axum::http::HeaderMap::new();
+            headers.insert(crate::FORWARDED_FOR_HEADER, HeaderValue::from_str(forwarded).unwrap());
+            headers.insert(crate::REAL_IP_HEADER, HeaderValue::from_static("192.0.2.9"));
+            headers
+        };
+        let proxy = SocketAddr::from(([10, 1, 2, 3], 4000));
+        let client = SocketAddr::from(([203, 0, 113, 7], 4000));
+
+        // the right-most hop that isn't a proxy, the ones on its left come from the client
+        assert_eq!(forwarded_ip(&headers("1.1.1.1, 198.51.100.4, 10.0.0.2"), proxy, &proxies), "198.51.100.4");
+        assert_eq!(forwarded_ip(&headers("garbage, 198.51.100.4"), proxy, &proxies), "198.51.100.4");
+        // only proxies in the chain, the proxy told the client in X-Real-IP
+        assert_eq!(forwarded_ip(&headers("10.0.0.2"), proxy, &proxies), "192.0.2.9");
+        assert_eq!(forwarded_ip(&headers("1.1.1.1"), SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 4000)), &proxies), "1.1.1.1");
+        // the headers of any other peer are ignored
+        assert_eq!(forwarded_ip(&headers("1.1.1.1"), client, &proxies), "203.0.113.7");
+        assert_eq!(forwarded_ip(&headers("1.1.1.1"), proxy, &TrustedProxies::default()), "10.1.2.3");
+    }
+
+    #[test]
+    fn test_is_https() {
+        let config = SecurityHeadersConfig { proto_header: "x-forwarded-proto".to_string(), ..SecurityHeadersConfig::default() };
+        let proxies = TrustedProxies::parse(["10.0.0.0/8"]);
+        let request = |peer: [u8; 4], proto: Option<&str>, uri: &str| {
+            let header = proto.map(|v| (header::HeaderName::from_static("x-forwarded-proto"), v.to_string()));
+            let mut request = get_request(uri, header);
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from((peer, 4000))));
+            request
+        };
+
+        assert!(is_https(&request([10, 0, 0, 2], Some("https"), "/"), &config, &proxies));
+        assert!(!is_https(&request([10, 0, 0, 2], Some("http"), "/"), &config, &proxies));
+        // a client can't claim HTTPS, only the proxies are trusted to tell it
+        assert!(!is_https(&request([203, 0, 113, 7], Some("https"), "/"), &config, &proxies));
+        assert!(!is_https(&request([10, 0, 0, 2], Some("https"), "/"), &config, &TrustedProxies::default()));
+        assert!(is_https(&request([203, 0, 113, 7], Some("http"), "https://example.com/"), &config, &proxies));
+    }
+}
@@ -1,16 +1,47 @@
 use proc_macro::TokenStream;
 use quote::quote;
 use heck::ToUpperCamelCase;
-use syn::{parse_macro_input, ItemTrait, FnArg, PatType, ReturnType, parse_quote};
+use syn::{ItemTrait, FnArg, PatType, ReturnType, parse_quote};
 
+/// Generates the rpc params/result enums and the `RpcTrait` wrapper of a service trait
+/// The service name defaults to the lowercased trait name without `trait`,
+/// `#[remote_trait(name = "auth.user.v2")]` sets it explicitly.
+/// Methods returning `()` also handle pushed messages, pushing to any other method fails with
+/// `ERROR_CODE_RPC_NOT_IMPLEMENTED`.
+/// `#[timeout_ms = 30000]` on a method sets the rpc timeout of its calls through `Node::call`
+/// Methods returning `types::Result<T>` send their error back as an error reply, other `Result`s are refused.
+/// Only `async` methods are rpcs, synchronous methods with a default body are helpers kept as they are
+/// The attributes of the methods, e.g. their docs, are kept on the rewritten trait
 #[proc_macro_attribute]
-pub fn remote_trait(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    let mut input = parse_macro_input!(item as ItemTrait);
+pub fn remote_trait(attr: TokenStream, item: TokenStream) -> TokenStream {
+    match expand(attr.into(), item.into()) {
+        Ok(v) => v.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Expansion of `remote_trait`, on `proc_macro2` tokens so it can be tested
+fn expand(attr: proc_macro2::TokenStream, item: proc_macro2::TokenStream) -> syn::Result<proc_macro2::TokenStream> {
+    let mut service_name: Option<syn::LitStr> = None;
+    let attr_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            service_name = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported remote_trait attribute, expected `name`"))
+        }
+    });
+    syn::parse::Parser::parse2(attr_parser, attr)?;
+    if let Some(name) = &service_name && let Err(message) = validate_service_name(&name.value()) {
+        return Err(syn::Error::new(name.span(), message));
+    }
+
+    let mut input: ItemTrait = syn::parse2(item)?;
     let trait_name = &input.ident;
 
     let params_enum_name = syn::Ident::new(&format!("{}_params", trait_name).to_upper_camel_case(), trait_name.span());
     let result_enum_name = syn::Ident::new(&format!("{}_result", trait_name).to_upper_camel_case(), trait_name.span());
-    let server_struct_name = syn::Ident::new(&format!("{}_rpc_server", trait_name).to_upper_camel_case(), trait_name.span());    
+    let server_struct_name = syn::Ident::new(&format!("{}_rpc_wrapper", trait_name).to_upper_camel_case(), trait_name.span());    
     let client_struct_name = syn::Ident::new(&format!("{}_rpc_client", trait_name).to_upper_camel_case(), trait_name.span());
     // input.supertraits.push(parse_quote!(Sized + Clone + Send + Sync));
     input.supertraits.push(parse_quote!(Sized));
@@ -21,21 +52,38 @@ pub fn remote_trait(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let mut param_variants = vec![];
     let mut result_variants = vec![];
     let mut rpc_arms = vec![];
+    let mut push_arms = vec![];
+    let mut has_rpc_only = false;
+    let mut timeout_arms = vec![];
+    let mut has_default_timeout = false;
+    let mut error_arms = vec![];
     let mut client_impls = vec![];
 
     for item in &mut input.items {
         if let syn::TraitItem::Fn(m) = item {
+            validate_asyncness(m)?;
+            if m.sig.asyncness.is_none() {
+                continue;
+            }
             let method_name = &m.sig.ident;
             let variant_name = syn::Ident::new(&method_name.to_string().to_upper_camel_case(), method_name.span());
 
+            validate_receiver(&m.sig)?;
+            let timeout_ms = take_timeout_ms(&mut m.attrs)?;
+            match timeout_ms {
+                Some(ms) => timeout_arms.push(quote! {
+                    #params_enum_name::#variant_name(..) => Some(std::time::Duration::from_millis(#ms))
+                }),
+                None => has_default_timeout = true,
+            }
             m.sig.inputs.insert(1, parse_quote!(context: std::sync::Arc<Self::Context>));
 
-            // 参数类型列表
-            let param_types: Vec<_> = m.sig.inputs.iter().skip(2).map(|arg| {
+            // 参数类型列表, methods without params get a variant without fields
+            let param_types: Vec<_> = m.sig.inputs.iter().skip(2).filter_map(|arg| {
                 if let FnArg::Typed(PatType { ty, .. }) = arg {
-                    ty
+                    Some(ty)
                 } else {
-                    panic!("Unexpected receiver")
+                    None
                 }
             }).collect();
 
@@ -53,6 +101,13 @@ pub fn remote_trait(_attr: TokenStream, item: TokenStream) -> TokenStream {
                 #variant_name(#ret_type)
             });
 
+            // methods returning a Result send their Err back as an error reply
+            if returns_result(&m.sig.output)? {
+                error_arms.push(quote! {
+                    #result_enum_name::#variant_name(Err(e)) => Err(e)
+                });
+            }
+
             // rpc match 分支
             let param_names: Vec<_> = (0..param_types.len())
                 .map(|i| syn::Ident::new(&format!("p{}", i), proc_macro2::Span::call_site()))
@@ -64,6 +119,17 @@ pub fn remote_trait(_attr: TokenStream, item: TokenStream) -> TokenStream {
                 }
             });
 
+            if returns_unit(&m.sig.output) {
+                push_arms.push(quote! {
+                    #params_enum_name::#variant_name(#(#param_names),*) => {
+                        self.#method_name(context, #(#param_names),*).await;
+                        Ok(())
+                    }
+                });
+            } else {
+                has_rpc_only = true;
+            }
+
             client_impls.push(quote! {
                 async fn #method_name(context, #(#param_types),*) -> #variant_name(#ret_type) {
 
@@ -72,10 +138,45 @@ pub fn remote_trait(_attr: TokenStream, item: TokenStream) -> TokenStream {
         }
     }
 
-    let lowercase_trait_name = trait_name.to_string().to_lowercase().replace("trait", "");
+    let into_reply = if error_arms.is_empty() {
+        quote! { Ok(result) }
+    } else {
+        quote! {
+            match result {
+                #(#error_arms,)*
+                result => Ok(result),
+            }
+        }
+    };
+
+    let lowercase_trait_name = match &service_name {
+        Some(name) => name.value(),
+        None => trait_name.to_string().to_lowercase().replace("trait", ""),
+    };
+
+    if has_default_timeout || timeout_arms.is_empty() {
+        timeout_arms.push(quote! {
+            _ => None
+        });
+    }
+
+    if has_rpc_only {
+        push_arms.push(quote! {
+            _ => Err(types::ERROR_CODE_RPC_NOT_IMPLEMENTED.into())
+        });
+    }
 
     input.attrs.push(parse_quote!(#[async_trait::async_trait]));
 
+    input.items.insert(0, parse_quote!( 
+        async fn __push_call(&self,context: std::sync::Arc<Self::Context>, params: #params_enum_name) -> types::Result<()>
+        {
+            match params {
+                #(#push_arms),*
+            }
+        }
+    ));
+
     input.items.insert(0, parse_quote!( 
         async fn __rpc_call(&self,context: std::sync::Arc<Self::Context>, params: #params_enum_name) -> #result_enum_name
         {
@@ -85,6 +186,18 @@ pub fn remote_trait(_attr: TokenStream, item: TokenStream) -> TokenStream {
         }
     ));
 
+    input.items.insert(0, parse_quote!( fn codec(&self) -> Option<types::Codec> {
+        None
+    }));
+
+    input.items.insert(0, parse_quote!( fn deduplicate(&self) -> bool {
+        false
+    }));
+
+    input.items.insert(0, parse_quote!( fn version(&self) -> &str {
+        ""
+    }));
+
     input.items.insert(0, parse_quote!( fn name(&self) -> &str {
         #lowercase_trait_name
     }));
@@ -99,6 +212,14 @@ pub fn remote_trait(_attr: TokenStream, item: TokenStream) -> TokenStream {
         pub enum #params_enum_name {
             #(#param_variants),*
         }
+        impl crate::app::RpcParams for #params_enum_name {
+            fn timeout(&self) -> Option<std::time::Duration> {
+                match self {
+                    #(#timeout_arms),*
+                }
+            }
+        }
+
         #[derive(Debug, bitcode::Encode, bitcode::Decode, serde::Serialize, serde::Deserialize)]
         pub enum #result_enum_name {
             #(#result_variants),*
@@ -117,9 +238,29 @@ pub fn remote_trait(_attr: TokenStream, item: TokenStream) -> TokenStream {
                 self.0.name()
             }
 
+            fn version(&self) -> &str {
+                self.0.version()
+            }
+
+            fn deduplicate(&self) -> bool {
+                self.0.deduplicate()
+            }
+
+            fn codec(&self) -> Option<types::Codec> {
+                self.0.codec()
+            }
+
             async fn rpc_call(&self, context: std::sync::Arc<Self::Context>, params: Self::Params) -> Self::Result {
                 self.0.__rpc_call(context, params).await
             }
+
+            async fn on_push(&self, context: std::sync::Arc<Self::Context>, params: Self::Params) -> types::Result<()> {
+                self.0.__push_call(context, params).await
+            }
+
+            fn into_reply(result: Self::Result) -> std::result::Result<Self::Result, types::Error> {
+                #into_reply
+            }
         }
 
         #[derive(Debug, Clone)]
@@ -132,5 +273,170 @@ pub fn remote_trait(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
     };
 
-    TokenStream::from(expanded)
+    Ok(expanded)
+}
+
+/// Whether the method returns a `Result`, either `Result<T, types::Error>` or the `types::Result<T>` alias
+/// Its error is sent back as a `types::Error`, so any other `Result`, e.g. `anyhow::Result<T>`, is refused
+fn returns_result(output: &ReturnType) -> syn::Result<bool> {
+    let ReturnType::Type(_, ty) = output else {
+        return Ok(false);
+    };
+    let syn::Type::Path(path) = ty.as_ref() else {
+        return Ok(false);
+    };
+    let Some(segment) = path.path.segments.last().filter(|v| v.ident == "Result") else {
+        return Ok(false);
+    };
+    let args: Vec<_> = match &segment.arguments {
+        syn::PathArguments::AngleBracketed(v) => v.args.iter().collect(),
+        _ => Vec::new(),
+    };
+    let is_types_result = args.len() == 1 && path_ends_with(&path.path, &["types", "Result"]);
+    let has_types_error = args.len() == 2 && matches!(
+        args[1],
+        syn::GenericArgument::Type(syn::Type::Path(error)) if path_ends_with(&error.path, &["types", "Error"])
+    );
+    if is_types_result || has_types_error {
+        return Ok(true);
+    }
+    Err(syn::Error::new_spanned(
+        ty,
+        "remote_trait methods returning a `Result` have to fail with `types::Error`, e.g. `types::Result<T>` or `Result<T, types::Error>`",
+    ))
+}
+
+/// Whether the last segments of the path are the given idents, e.g. `types::Error` for `::types::Error`
+fn path_ends_with(path: &syn::Path, idents: &[&str]) -> bool {
+    path.segments.len() >= idents.len()
+        && path.segments.iter().rev().zip(idents.iter().rev()).all(|(segment, ident)| segment.ident == ident)
+}
+
+/// Removes the `#[timeout_ms = N]` attribute of a method and returns its value
+fn take_timeout_ms(attrs: &mut Vec<syn::Attribute>) -> syn::Result<Option<u64>> {
+    let mut timeout_ms = None;
+    let mut error = None;
+    attrs.retain(|attr| {
+        if !attr.path().is_ident("timeout_ms") {
+            return true;
+        }
+        let value = attr.meta.require_name_value().and_then(|v| match &v.value {
+            syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(lit), .. }) => lit.base10_parse::<u64>(),
+            value => Err(syn::Error::new_spanned(value, "expected the timeout in milliseconds, e.g. `#[timeout_ms = 30000]`")),
+        });
+        match value {
+            Ok(v) => timeout_ms = Some(v),
+            Err(e) => error = Some(e),
+        }
+        false
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(timeout_ms),
+    }
+}
+
+/// Service methods are called on a shared handler, so they have to take `&self`
+fn validate_receiver(sig: &syn::Signature) -> syn::Result<()> {
+    let message = match sig.inputs.first() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() && receiver.mutability.is_none() => return Ok(()),
+        Some(FnArg::Receiver(receiver)) if receiver.mutability.is_some() => {
+            "remote_trait methods can't take `&mut self`, the handler is shared by concurrent calls, use interior mutability instead"
+        }
+        Some(FnArg::Receiver(_)) => "remote_trait methods have to take `&self`, the handler is not consumed by a call",
+        _ => "remote_trait methods have to take `&self`",
+    };
+    let span = match sig.inputs.first() {
+        Some(arg) => syn::spanned::Spanned::span(arg),
+        None => sig.ident.span(),
+    };
+    Err(syn::Error::new(span, format!("`{}`: {message}", sig.ident)))
+}
+
+/// Synchronous methods aren't rpcs, they need a default body to be left out of the service
+fn validate_asyncness(method: &syn::TraitItemFn) -> syn::Result<()> {
+    if method.sig.asyncness.is_some() || method.default.is_some() {
+        return Ok(());
+    }
+    let message = format!(
+        "`{}`: only `async` methods are supported as rpcs, make it `async` or give the helper a default body",
+        method.sig.ident
+    );
+    Err(syn::Error::new(method.sig.fn_token.span, message))
+}
+
+/// Whether the method returns nothing, such methods handle pushed messages
+fn returns_unit(output: &ReturnType) -> bool {
+    match output {
+        ReturnType::Default => true,
+        ReturnType::Type(_, ty) => matches!(ty.as_ref(), syn::Type::Tuple(tuple) if tuple.elems.is_empty()),
+    }
+}
+
+/// A service name is used as a single chunk of the zenoh key expressions, e.g. `@rpc/{service}/{zid}`
+fn validate_service_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("service name must not be empty".to_string());
+    }
+    if let Some(c) = name.chars().find(|c| matches!(c, '/' | '*' | '$' | '?' | '#' | '@') || c.is_whitespace()) {
+        return Err(format!("service name `{name}` contains `{c}`, which is not allowed in a key expression chunk"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_attrs_kept() {
+        let item = quote! {
+            pub trait DocTrait {
+                /// Replies pong
+                #[allow(clippy::needless_lifetimes)]
+                #[timeout_ms = 1000]
+                async fn ping(&self, zid: String) -> String;
+            }
+        };
+        let expanded: syn::File = syn::parse2(expand(quote! {}, item).unwrap()).unwrap();
+        let method = expanded.items.iter()
+            .find_map(|v| match v {
+                syn::Item::Trait(v) => Some(v),
+                _ => None,
+            })
+            .and_then(|v| v.items.iter().find_map(|v| match v {
+                syn::TraitItem::Fn(v) if v.sig.ident == "ping" => Some(v),
+                _ => None,
+            }))
+            .unwrap();
+        let attrs: Vec<_> = method.attrs.iter().map(|v| quote!(#v).to_string()).collect();
+        assert_eq!(attrs, [
+            quote!(#[doc = r" Replies pong"]).to_string(),
+            quote!(#[allow(clippy::needless_lifetimes)]).to_string(),
+        ]);
+        // the context is inserted after the receiver
+        assert_eq!(method.sig.inputs.len(), 3);
+    }
+
+    #[test]
+    fn test_result_error_type() {
+        for output in [quote!(types::Result<String>), quote!(Result<String, types::Error>), quote!(std::result::Result<(), ::types::Error>)] {
+            let item = quote! {
+                pub trait ResultTrait {
+                    async fn call(&self) -> #output;
+                }
+            };
+            assert!(expand(quote! {}, item).is_ok(), "{output}");
+        }
+        // the error of any other result can't be sent back, it fails at the return type
+        for output in [quote!(std::io::Result<String>), quote!(anyhow::Result<String>), quote!(Result<String, MyError>), quote!(Result<String>)] {
+            let item = quote! {
+                pub trait ResultTrait {
+                    async fn call(&self) -> #output;
+                }
+            };
+            let error = expand(quote! {}, item).unwrap_err();
+            assert!(error.to_string().contains("have to fail with `types::Error`"), "{output}");
+        }
+    }
 }
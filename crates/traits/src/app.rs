@@ -2,11 +2,65 @@ pub trait ContextTrait: Sized {
     fn session(&self) -> &zenoh::Session;
 }
 
+/// Params of a service method generated by `remote_trait`
+pub trait RpcParams {
+    /// Rpc timeout of the method, set by its `#[timeout_ms]` attribute
+    fn timeout(&self) -> Option<std::time::Duration>;
+}
+
 #[async_trait::async_trait]
 pub trait RpcTrait: Sized + Clone {
     type Context: ContextTrait + Send + Unpin + Sync + 'static;
-    type Params: bitcode::Encode + bitcode::DecodeOwned + Send + Unpin + Sync + 'static;
-    type Result: bitcode::Encode + bitcode::DecodeOwned + Send + Unpin + Sync + 'static;
+    type Params: bitcode::Encode + bitcode::DecodeOwned + serde::Serialize + serde::de::DeserializeOwned + Send + Unpin + Sync + 'static;
+    type Result: bitcode::Encode + bitcode::DecodeOwned + serde::Serialize + serde::de::DeserializeOwned + Send + Unpin + Sync + 'static;
     fn name(&self) -> &str;
+
+    /// Version of the service served by the handler, requests asking for it are routed to its instances first.
+    /// The default serves the service unversioned.
+    fn version(&self) -> &str {
+        ""
+    }
+
+    /// Deduplicates requests by their `idempotency_key`, a duplicate replays the replies of the first request,
+    /// and a duplicate push is not handled again. Requests without a key are always handled.
+    /// The default handles every request.
+    fn deduplicate(&self) -> bool {
+        false
+    }
+
+    /// Codec of the params and results of the service, requests in another codec are rejected.
+    /// The default follows the codec of each request.
+    fn codec(&self) -> Option<types::Codec> {
+        None
+    }
     async fn rpc_call(&self,context: std::sync::Arc<Self::Context>, params: Self::Params) -> Self::Result;
-}
\ No newline at end of file
+
+    /// Splits an application error out of a result so it is sent back as an error reply.
+    /// The default treats every result as a success.
+    fn into_reply(result: Self::Result) -> std::result::Result<Self::Result, types::Error> {
+        Ok(result)
+    }
+
+    /// Replies a result as raw bytes with its content type and headers instead of encoding it, e.g. an image or CSV.
+    /// The default encodes every result with the codec of the request.
+    fn into_raw(_result: &Self::Result) -> Option<types::RawReply> {
+        None
+    }
+
+    /// Emits zero or more results for a single request, each one is sent back as a separate reply.
+    /// The default forwards the result of `rpc_call` as the only reply.
+    async fn rpc_stream(&self, context: std::sync::Arc<Self::Context>, params: Self::Params, sender: tokio::sync::mpsc::Sender<Self::Result>) {
+        let result = self.rpc_call(context, params).await;
+        if sender.send(result).await.is_err() {
+            tracing::debug!("{}:{} rpc stream receiver dropped", file!(), line!());
+        }
+    }
+
+    /// Handles a message pushed to this service, no reply is sent back to the publisher.
+    /// The error is only reported to publishers waiting for an acknowledgement.
+    /// The default runs `rpc_call` and discards its result.
+    async fn on_push(&self, context: std::sync::Arc<Self::Context>, params: Self::Params) -> types::Result<()> {
+        self.rpc_call(context, params).await;
+        Ok(())
+    }
+}
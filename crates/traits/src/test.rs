@@ -3,4 +3,21 @@ use macros::remote_trait;
 #[remote_trait]
 pub trait PingTrait {
     async fn ping(&self, zid: String) -> String;
-}
\ No newline at end of file
+    #[timeout_ms = 30000]
+    async fn echo(&self, message: String) -> types::Result<String>;
+    /// Reply to a ping, a helper which is not an rpc
+    fn pong(&self) -> String {
+        "Pong".to_string()
+    }
+}
+
+#[remote_trait(name = "ping.named.v2")]
+pub trait NamedPingTrait {
+    async fn ping(&self, zid: String) -> String;
+}
+
+#[remote_trait(name = "notify")]
+pub trait NotifyTrait {
+    async fn notify(&self, message: String);
+    async fn notified(&self) -> Vec<String>;
+}
@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode, response::{IntoResponse, Response}, Json
+    http::{header, HeaderName, HeaderValue, StatusCode}, response::{IntoResponse, Response}, Json
 };
 
 pub const ERROR_CODE_SERVICE_NOT_FOUND: (i32, &str) = (10001, "service not found");
@@ -7,6 +7,18 @@ pub const ERROR_CODE_INTERNAL_ERROR: (i32, &str) = (10002, "internal error");
 pub const ERROR_CODE_RPC_TIMEOUT: (i32, &str) = (10003, "rpc timeout");
 pub const ERROR_CODE_DESERIALIZE: (i32, &str) = (10004, "internal error");
 pub const ERROR_CODE_RPC_NOT_IMPLEMENTED: (i32, &str)= (10005, "rpc not implemented");
+pub const ERROR_CODE_UNAUTHORIZED: (i32, &str) = (10006, "unauthorized");
+pub const ERROR_CODE_PROTOCOL_MISMATCH: (i32, &str) = (10007, "protocol version mismatch");
+pub const ERROR_CODE_TOO_MANY_REQUESTS: (i32, &str) = (10008, "too many requests");
+pub const ERROR_CODE_PAYLOAD_TOO_LARGE: (i32, &str) = (10009, "payload too large");
+pub const ERROR_CODE_OVERLOADED: (i32, &str) = (10010, "service overloaded");
+pub const ERROR_CODE_INVALID_VERSION: (i32, &str) = (10011, "invalid version");
+
+/// Version of the wire format of `ClusterRequest`, bumped whenever its fields change
+/// Sent in the first 2 bytes of every request, ahead of the encoded `ClusterRequest`, so nodes of different versions
+/// reject each other's requests before trying to decode them. Replies aren't versioned, `ClusterResponse` and `Error`
+/// from a node of another version fail to decode or misdecode
+pub const PROTOCOL_VERSION: u16 = 12;
 
 type ErrorType = (i32, &'static str);
 
@@ -14,6 +26,40 @@ type ErrorType = (i32, &'static str);
 pub struct Error {
     pub code: i32,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<ErrorData>,
+    /// Time the caller should wait before retrying, sent in the `Retry-After` header of the HTTP response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+/// Structured details of an error, e.g. the fields that failed validation
+/// Carried as JSON text so it can be encoded by bitcode, serialized by serde as a plain JSON value
+#[derive(Debug, Clone, PartialEq, bitcode::Encode, bitcode::Decode)]
+pub struct ErrorData(String);
+
+impl ErrorData {
+    pub fn value(&self) -> serde_json::Value {
+        serde_json::from_str(&self.0).unwrap_or_default()
+    }
+}
+
+impl From<serde_json::Value> for ErrorData {
+    fn from(value: serde_json::Value) -> Self {
+        ErrorData(value.to_string())
+    }
+}
+
+impl serde::Serialize for ErrorData {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.value().serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ErrorData {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        serde_json::Value::deserialize(deserializer).map(Into::into)
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -25,10 +71,53 @@ impl std::fmt::Display for Error {
 }
 impl std::error::Error for Error {}
 
+impl Error {
+    pub fn with_data(code: i32, message: impl Into<String>, data: serde_json::Value) -> Self {
+        Error {
+            code,
+            message: message.into(),
+            data: Some(data.into()),
+            retry_after_ms: None,
+        }
+    }
+
+    /// Hints the caller to back off for the duration before retrying, e.g. when throttled
+    pub fn with_retry_after(mut self, retry_after: std::time::Duration) -> Self {
+        self.retry_after_ms = Some(u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX));
+        self
+    }
+
+    /// HTTP status matching the error code
+    /// Application codes, outside the 10000 range of the framework, are served as is when they are 4xx or 5xx statuses,
+    /// and as 400 otherwise, other framework codes map to 500
+    pub fn status_code(&self) -> u16 {
+        match self.code {
+            c if c == ERROR_CODE_SERVICE_NOT_FOUND.0 => 404,
+            c if c == ERROR_CODE_RPC_TIMEOUT.0 => 504,
+            c if c == ERROR_CODE_RPC_NOT_IMPLEMENTED.0 => 501,
+            c if c == ERROR_CODE_UNAUTHORIZED.0 => 401,
+            c if c == ERROR_CODE_TOO_MANY_REQUESTS.0 => 429,
+            c if c == ERROR_CODE_PAYLOAD_TOO_LARGE.0 => 413,
+            c if c == ERROR_CODE_OVERLOADED.0 => 503,
+            c if c == ERROR_CODE_INVALID_VERSION.0 => 400,
+            c if c == ERROR_CODE_INTERNAL_ERROR.0 || c == ERROR_CODE_DESERIALIZE.0 => 500,
+            10000..=10999 => 500,
+            c @ 400..=599 => c as u16,
+            _ => 400,
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let body = Json(self);
-        (StatusCode::OK, body).into_response()
+        let status_code = StatusCode::from_u16(self.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        // Retry-After is in whole seconds, rounded up not to retry early
+        let retry_after = self.retry_after_ms.map(|v| v.div_ceil(1000));
+        let mut response = (status_code, Json(self)).into_response();
+        if let Some(retry_after) = retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 }
 
@@ -37,16 +126,104 @@ impl From<ErrorType> for Error {
         Error{
             code: value.0,
             message: value.1.to_string(),
+            data: None,
+            retry_after_ms: None,
         }
     }
 }
 
-#[derive(Debug, bitcode::Encode, bitcode::Decode, serde::Serialize, serde::Deserialize)]
+/// Codec of a compressed `ClusterRequest` payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, bitcode::Encode, bitcode::Decode, serde::Serialize, serde::Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(ERROR_CODE_DESERIALIZE.into()),
+        }
+    }
+}
+
+/// Encoding of the params of a `ClusterRequest` payload and of the results replied to it
+/// `Json` trades the compactness of bitcode for payloads readable by tooling and non-Rust clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, bitcode::Encode, bitcode::Decode, serde::Serialize, serde::Deserialize)]
+pub enum Codec {
+    #[default]
+    Bitcode,
+    Json,
+}
+
+impl Codec {
+    /// Encodes a value, only JSON can fail, e.g. on maps with non-string keys
+    pub fn encode<T>(&self, value: &T) -> Result<Vec<u8>>
+    where
+        T: bitcode::Encode + serde::Serialize,
+    {
+        match self {
+            Codec::Bitcode => Ok(bitcode::encode(value)),
+            Codec::Json => serde_json::to_vec(value).map_err(|_| ERROR_CODE_INTERNAL_ERROR.into()),
+        }
+    }
+
+    /// Decodes a value, failing with `ERROR_CODE_DESERIALIZE`
+    pub fn decode<T>(&self, bytes: &[u8]) -> Result<T>
+    where
+        T: bitcode::DecodeOwned + serde::de::DeserializeOwned,
+    {
+        match self {
+            Codec::Bitcode => bitcode::decode(bytes).map_err(|_| ERROR_CODE_DESERIALIZE.into()),
+            Codec::Json => serde_json::from_slice(bytes).map_err(|_| ERROR_CODE_DESERIALIZE.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, bitcode::Encode, bitcode::Decode, serde::Serialize, serde::Deserialize)]
 pub struct ClusterRequest{
     pub zid: String,
     pub version: String,
     pub query: String,
     pub payload: Vec<u8>,
+    pub user_id: Option<String>,   // Subject of the verified token of the caller
+    pub client_ip: Option<String>, // Address of the client that made the request to the gateway
+    pub method: String,            // HTTP method of the request, empty when not made over HTTP
+    pub query_string: String,      // Raw query string of the request url, without the leading `?`
+    pub compression: Compression,  // Codec the payload is compressed with, set by the sending node
+    pub trace_id: String,          // Id correlating the logs of the request across services, set by the sending node when empty
+    pub codec: Codec,              // Encoding of the params in the payload and of the results replied
+    pub idempotency_key: Option<String>, // Key of the request for services deduplicating requests, retries of a request keep its key
+    pub deadline_unix_ms: Option<i64>,   // Time the caller stops waiting for the reply, set by the sending node from its timeout
+    pub request_id: String,              // Id of the rpc, set by the sending node so it can cancel it once it gives up on the reply
+}
+
+impl Default for ClusterRequest {
+    fn default() -> Self {
+        Self {
+            zid: String::new(),
+            version: String::new(),
+            query: String::new(),
+            payload: Vec::new(),
+            user_id: None,
+            client_ip: None,
+            method: String::new(),
+            query_string: String::new(),
+            compression: Compression::None,
+            trace_id: String::new(),
+            codec: Codec::Bitcode,
+            idempotency_key: None,
+            deadline_unix_ms: None,
+            request_id: String::new(),
+        }
+    }
 }
 
 #[derive(Debug, bitcode::Encode, bitcode::Decode, serde::Serialize, serde::Deserialize)]
@@ -54,20 +231,164 @@ pub struct ClusterResponse{
     pub zid: String,
     pub status: u16,
     pub payload: Option<Vec<u8>>,
+    #[serde(default)]
+    pub content_type: Option<String>,   // Content type of a raw payload, the payload is JSON when unset
+    #[serde(default)]
+    pub headers: Vec<(String, String)>, // Extra HTTP headers sent along the payload by the gateway
+}
+
+/// Result replied as raw bytes with its content type instead of encoded, e.g. an image or a CSV export
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawReply {
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl ClusterResponse {
+    /// Whether the payload is JSON, the case of every response without a content type
+    pub fn is_json(&self) -> bool {
+        self.content_type.as_deref().is_none_or(|v| {
+            let mime = v.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+            mime == "application/json" || mime.ends_with("+json")
+        })
+    }
 }
 
 impl IntoResponse for ClusterResponse {
     fn into_response(self) -> Response {
         let status_code = StatusCode::from_u16(self.status).unwrap_or_default();
-        let json = match self.payload {
-            Some(v) => {
-                serde_json::from_slice(&v).unwrap_or_default()
-            },
-            None => {
-                serde_json::Value::Null
-            }   
+        let mut response = if self.is_json() {
+            let json = match self.payload {
+                Some(v) => {
+                    serde_json::from_slice(&v).unwrap_or_default()
+                },
+                None => {
+                    serde_json::Value::Null
+                }
+            };
+            (status_code, Json(json)).into_response()
+        } else {
+            let content_type = self.content_type.as_deref().and_then(|v| HeaderValue::from_str(v).ok())
+                .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+            (status_code, [(header::CONTENT_TYPE, content_type)], self.payload.unwrap_or_default()).into_response()
         };
-        let body = Json(json);
-        (status_code, body).into_response()
+        // headers that aren't valid HTTP are dropped rather than failing the whole response
+        for (name, value) in self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+                response.headers_mut().append(name, value);
+            }
+        }
+        response
+    }
+}
+
+/// Health of a node, answered by every node on `@health/{service}/{zid}`
+#[derive(Debug, Clone, PartialEq, bitcode::Encode, bitcode::Decode, serde::Serialize, serde::Deserialize)]
+pub struct NodeHealth {
+    pub zid: String,
+    pub uptime_secs: u64,   // Seconds since the node was created
+    pub in_flight: usize,   // Rpc and push requests being handled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec() {
+        for codec in [Codec::Bitcode, Codec::Json] {
+            let bytes = codec.encode(&("hello".to_string(), 7u32)).unwrap();
+            assert_eq!(codec.decode::<(String, u32)>(&bytes).unwrap(), ("hello".to_string(), 7));
+            assert_eq!(codec.decode::<u32>(b"\xff").unwrap_err().code, ERROR_CODE_DESERIALIZE.0);
+        }
+        assert_eq!(Codec::Json.encode(&vec![1u8, 2]).unwrap(), b"[1,2]");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(Error::from(ERROR_CODE_SERVICE_NOT_FOUND).status_code(), 404);
+        assert_eq!(Error::from(ERROR_CODE_RPC_TIMEOUT).status_code(), 504);
+        assert_eq!(Error::from(ERROR_CODE_RPC_NOT_IMPLEMENTED).status_code(), 501);
+        assert_eq!(Error::from(ERROR_CODE_UNAUTHORIZED).status_code(), 401);
+        assert_eq!(Error::from(ERROR_CODE_TOO_MANY_REQUESTS).status_code(), 429);
+        assert_eq!(Error::from(ERROR_CODE_PAYLOAD_TOO_LARGE).status_code(), 413);
+        assert_eq!(Error::from(ERROR_CODE_OVERLOADED).status_code(), 503);
+        assert_eq!(Error::from(ERROR_CODE_INVALID_VERSION).status_code(), 400);
+        assert_eq!(Error::from(ERROR_CODE_INTERNAL_ERROR).status_code(), 500);
+        assert_eq!(Error::from(ERROR_CODE_DESERIALIZE).status_code(), 500);
+        assert_eq!(Error::from(ERROR_CODE_PROTOCOL_MISMATCH).status_code(), 500);
+
+        // application errors are the client's, unless they tell otherwise
+        assert_eq!(Error::with_data(400, "validation failed", serde_json::Value::Null).status_code(), 400);
+        assert_eq!(Error::from((409, "conflict")).status_code(), 409);
+        assert_eq!(Error::from((503, "maintenance")).status_code(), 503);
+        assert_eq!(Error::from((20001, "insufficient balance")).status_code(), 400);
+        assert_eq!(Error::from((-1, "failed")).status_code(), 400);
+        assert_eq!(Error::from((200, "not an error status")).status_code(), 400);
+
+        let response = Error::from(ERROR_CODE_RPC_TIMEOUT).into_response();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn test_response_content_type() {
+        let response = ClusterResponse {
+            zid: "".to_string(),
+            status: 200,
+            payload: Some(b"{\"a\":1}".to_vec()),
+            content_type: None,
+            headers: vec![("cache-control".to_string(), "no-store".to_string()), ("bad header".to_string(), "x".to_string())],
+        };
+        assert!(response.is_json());
+        let response = response.into_response();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        assert_eq!(response.headers().len(), 2);
+
+        let response = ClusterResponse {
+            zid: "".to_string(),
+            status: 200,
+            payload: Some(b"a,b\n1,2\n".to_vec()),
+            content_type: Some("text/csv; charset=utf-8".to_string()),
+            headers: Vec::new(),
+        };
+        assert!(!response.is_json());
+        let response = response.into_response();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv; charset=utf-8");
+
+        let response = ClusterResponse { content_type: Some("application/problem+json".to_string()), payload: None, zid: "".to_string(), status: 400, headers: Vec::new() };
+        assert!(response.is_json());
+    }
+
+    #[test]
+    fn test_error_data() {
+        let data = serde_json::json!({"fields": {"email": "invalid format"}});
+        let error = Error::with_data(400, "validation failed", data.clone());
+
+        let decoded: Error = bitcode::decode(&bitcode::encode(&error)).unwrap();
+        assert_eq!(decoded.data.unwrap().value(), data);
+
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["data"], data);
+        let decoded: Error = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.data.unwrap().value(), data);
+
+        let json = serde_json::to_value(Error::from(ERROR_CODE_INTERNAL_ERROR)).unwrap();
+        assert!(json.get("data").is_none());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let error = Error::from(ERROR_CODE_TOO_MANY_REQUESTS).with_retry_after(std::time::Duration::from_millis(1500));
+        let decoded: Error = bitcode::decode(&bitcode::encode(&error)).unwrap();
+        assert_eq!(decoded.retry_after_ms, Some(1500));
+        assert_eq!(serde_json::to_value(&error).unwrap()["retry_after_ms"], 1500);
+
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+        let response = Error::from(ERROR_CODE_TOO_MANY_REQUESTS).into_response();
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+    }
+}
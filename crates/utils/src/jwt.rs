@@ -1,47 +1,150 @@
-use jsonwebtoken::{DecodingKey, Validation};
+use jsonwebtoken::{DecodingKey, EncodingKey, Validation};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
+// Names of the registered claims, custom claims may not override them
+const REGISTERED_CLAIMS: [&str; 8] = ["aud", "exp", "iat", "iss", "nbf", "sub", "typ", "jti"];
+
+/// `typ` claim of the refresh tokens, access tokens have none
+pub const REFRESH_TOKEN_TYPE: &str = "refresh";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Claims {
+pub struct Claims {
     #[serde(skip_serializing_if = "Option::is_none")]
-    aud: Option<String>,         // Optional. Audience
-    exp: usize,                  // Required (validate_exp defaults to true in validation). Expiration time (as UTC timestamp)
+    pub aud: Option<String>,         // Optional. Audience
+    pub exp: usize,                  // Required (validate_exp defaults to true in validation). Expiration time (as UTC timestamp)
     #[serde(skip_serializing_if = "Option::is_none")]
-    iat: Option<usize>,          // Optional. Issued at (as UTC timestamp)
+    pub iat: Option<usize>,          // Optional. Issued at (as UTC timestamp)
     #[serde(skip_serializing_if = "Option::is_none")]
-    iss: Option<String>,         // Optional. Issuer
+    pub iss: Option<String>,         // Optional. Issuer
     #[serde(skip_serializing_if = "Option::is_none")]
-    nbf: Option<usize>,          // Optional. Not Before (as UTC timestamp)
+    pub nbf: Option<usize>,          // Optional. Not Before (as UTC timestamp)
     #[serde(skip_serializing_if = "Option::is_none")]
-    sub: Option<String>,         // Optional. Subject (whom token refers to)
+    pub sub: Option<String>,         // Optional. Subject (whom token refers to)
     #[serde(skip_serializing_if = "Option::is_none")]
-    typ: Option<String>,         // Optional. Type of token.
+    pub typ: Option<String>,         // Optional. Type of token.
     #[serde(skip_serializing_if = "Option::is_none")]
-    jti: Option<i64>,            // Optional. JWT ID. Unique identifier for the token
+    pub jti: Option<i64>,            // Optional. JWT ID. Unique identifier for the token
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,   // Custom claims, e.g. roles or tenant id
+}
+
+impl Claims {
+    /// Standard claims of a token for the subject, valid for `duration` seconds from now
+    fn new(uid: &str, duration: i64) -> Self {
+        let now = chrono::Utc::now();
+        let iat = now.timestamp() as usize;
+        // waits at most until the next millisecond, on a clock step back or 4096 ids within the millisecond
+        let jti = crate::snowflake::generate_id();
+        let exp = (now + chrono::Duration::try_seconds(duration).unwrap_or_default()).timestamp() as usize;
+        Claims {
+            sub: Some(uid.to_string()),
+            exp,
+            iat: Some(iat),
+            typ: None,
+            aud: None,
+            iss: None,
+            jti: Some(jti),
+            nbf: None,
+            extra: Map::new(),
+        }
+    }
+}
+
+/// Signing algorithm of the tokens
+/// `HS256` uses a shared secret, `RS256` a PEM encoded RSA private key to sign and public key to verify
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    #[default]
+    HS256,
+    RS256,
+}
+
+impl Algorithm {
+    fn encoding_key(&self, key: &[u8]) -> jsonwebtoken::errors::Result<EncodingKey> {
+        match self {
+            Algorithm::HS256 => Ok(EncodingKey::from_secret(key)),
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(key),
+        }
+    }
+
+    fn decoding_key(&self, key: &[u8]) -> jsonwebtoken::errors::Result<DecodingKey> {
+        match self {
+            Algorithm::HS256 => Ok(DecodingKey::from_secret(key)),
+            Algorithm::RS256 => DecodingKey::from_rsa_pem(key),
+        }
+    }
+}
+
+impl From<Algorithm> for jsonwebtoken::Algorithm {
+    fn from(value: Algorithm) -> Self {
+        match value {
+            Algorithm::HS256 => jsonwebtoken::Algorithm::HS256,
+            Algorithm::RS256 => jsonwebtoken::Algorithm::RS256,
+        }
+    }
 }
 
 pub fn create_token(uid: &str, key: &[u8]) -> String {
-    let now = chrono::Utc::now();
-    let iat = now.timestamp() as usize;
-    let jti = crate::snowflake::generate_id();
-    let duration: i64 = crate::vars::get_jwt_duration();
-    let exp = (now + chrono::Duration::try_seconds(duration).unwrap_or_default()).timestamp() as usize;
-    let claims = Claims {
-        sub: Some(uid.to_string()),
-        exp,
-        iat: Some(iat),
-        typ: None,
-        aud: None,
-        iss: None,
-        jti: Some(jti),
-        nbf: None,
+    create_token_with(uid, key, Algorithm::HS256)
+}
+
+pub fn create_token_rs256(uid: &str, private_pem: &[u8]) -> String {
+    create_token_with(uid, private_pem, Algorithm::RS256)
+}
+
+pub fn create_token_with(uid: &str, key: &[u8], algorithm: Algorithm) -> String {
+    let claims = Claims::new(uid, crate::vars::get_jwt_duration());
+    encode_claims(&claims, key, algorithm)
+}
+
+/// Creates an HS256 refresh token, valid for `REFRESH_TOKEN_DURATION` seconds
+/// Refresh tokens only mint new access tokens, `verify_token` rejects them
+pub fn create_refresh_token(uid: &str, key: &[u8]) -> String {
+    create_refresh_token_with(uid, key, Algorithm::HS256)
+}
+
+pub fn create_refresh_token_with(uid: &str, key: &[u8], algorithm: Algorithm) -> String {
+    let mut claims = Claims::new(uid, crate::vars::get_refresh_token_duration());
+    claims.typ = Some(REFRESH_TOKEN_TYPE.to_string());
+    encode_claims(&claims, key, algorithm)
+}
+
+/// Creates an HS256 token for the audience, issued by the issuer, checked by `verify_token_for`
+pub fn create_token_for(uid: &str, key: &[u8], aud: &str, iss: &str) -> String {
+    let mut claims = Claims::new(uid, crate::vars::get_jwt_duration());
+    claims.aud = Some(aud.to_string());
+    claims.iss = Some(iss.to_string());
+    encode_claims(&claims, key, Algorithm::HS256)
+}
+
+/// Creates an HS256 token carrying custom claims next to the standard ones
+/// Custom claims named like a registered claim (`sub`, `exp`, ...) are ignored
+pub fn create_token_with_claims(uid: &str, key: &[u8], extra: Map<String, Value>) -> String {
+    let mut claims = Claims::new(uid, crate::vars::get_jwt_duration());
+    for (name, value) in extra {
+        if REGISTERED_CLAIMS.contains(&name.as_str()) {
+            tracing::warn!("create jwt ignores custom claim {name}, it is a registered claim");
+            continue;
+        }
+        claims.extra.insert(name, value);
+    }
+    encode_claims(&claims, key, Algorithm::HS256)
+}
+
+fn encode_claims(claims: &Claims, key: &[u8], algorithm: Algorithm) -> String {
+    let key = match algorithm.encoding_key(key) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("create jwt failed, invalid key {e:?}");
+            return "".to_string();
+        }
     };
 
     match jsonwebtoken::encode(
-        &jsonwebtoken::Header::default(),
-        &claims,
-        &jsonwebtoken::EncodingKey::from_secret(key),
+        &jsonwebtoken::Header::new(algorithm.into()),
+        claims,
+        &key,
     ){
         Ok(v) => v,
         Err(e) => {
@@ -52,16 +155,101 @@ pub fn create_token(uid: &str, key: &[u8]) -> String {
 }
 
 pub fn verify_token(token: &str, key: &[u8]) -> Option<String> {
-    let mut validation = Validation::default();
+    verify_token_with(token, key, Algorithm::HS256)
+}
+
+pub fn verify_token_rs256(token: &str, public_pem: &[u8]) -> Option<String> {
+    verify_token_with(token, public_pem, Algorithm::RS256)
+}
+
+/// Verifies the token with the given algorithm and returns its subject
+/// Tokens signed with any other algorithm are rejected
+pub fn verify_token_with(token: &str, key: &[u8], algorithm: Algorithm) -> Option<String> {
+    verify_claims(token, key, algorithm)?.sub
+}
+
+/// Verifies the HS256 access token with each key in turn and returns its subject on the first success
+/// Lists the active key first then the retired ones still accepted while rotating the secret, tokens are only created with the active key
+pub fn verify_token_multi(token: &str, keys: &[&[u8]]) -> Option<String> {
+    keys.iter().find_map(|key| verify_token(token, key))
+}
+
+/// Verifies the access token with the given algorithm and returns all its claims, including the custom ones
+/// Refresh tokens are rejected
+pub fn verify_claims(token: &str, key: &[u8], algorithm: Algorithm) -> Option<Claims> {
+    verify_claims_with_leeway(token, key, algorithm, crate::vars::get_jwt_leeway())
+}
+
+/// Verifies the HS256 access token tolerating `leeway` seconds of clock skew on `exp` and `nbf`, returns its subject
+pub fn verify_token_with_leeway(token: &str, key: &[u8], leeway: u64) -> Option<String> {
+    verify_claims_with_leeway(token, key, Algorithm::HS256, leeway)?.sub
+}
+
+/// `verify_claims` tolerating `leeway` seconds of clock skew instead of `JWT_LEEWAY_SECONDS`
+pub fn verify_claims_with_leeway(token: &str, key: &[u8], algorithm: Algorithm, leeway: u64) -> Option<Claims> {
+    decode_claims(token, key, algorithm, &validation(algorithm, leeway)).filter(|v| v.typ.as_deref() != Some(REFRESH_TOKEN_TYPE))
+}
+
+/// Verifies the HS256 refresh token and returns its subject, access tokens are rejected
+pub fn verify_refresh_token(token: &str, key: &[u8]) -> Option<String> {
+    verify_refresh_token_with(token, key, Algorithm::HS256)
+}
+
+pub fn verify_refresh_token_with(token: &str, key: &[u8], algorithm: Algorithm) -> Option<String> {
+    decode_claims(token, key, algorithm, &validation(algorithm, crate::vars::get_jwt_leeway()))
+        .filter(|v| v.typ.as_deref() == Some(REFRESH_TOKEN_TYPE))?
+        .sub
+}
+
+/// Creates a new HS256 access token for the subject of the refresh token, `None` if it is not a valid refresh token
+pub fn refresh_access_token(refresh_token: &str, key: &[u8]) -> Option<String> {
+    let uid = verify_refresh_token(refresh_token, key)?;
+    Some(create_token(&uid, key))
+}
+
+/// Verifies the HS256 access token minted for the audience by the issuer, returns its subject
+/// Each expected claim given has to be in the token, tokens without it are rejected
+pub fn verify_token_for(token: &str, key: &[u8], expected_aud: Option<&str>, expected_iss: Option<&str>) -> Option<String> {
+    let mut validation = validation(Algorithm::HS256, crate::vars::get_jwt_leeway());
+    let mut required = vec!["exp"];
+    if let Some(aud) = expected_aud {
+        validation.validate_aud = true;
+        validation.set_audience(&[aud]);
+        required.push("aud");
+    }
+    if let Some(iss) = expected_iss {
+        validation.set_issuer(&[iss]);
+        required.push("iss");
+    }
+    validation.set_required_spec_claims(&required);
+    decode_claims(token, key, Algorithm::HS256, &validation)
+        .filter(|v| v.typ.as_deref() != Some(REFRESH_TOKEN_TYPE))?
+        .sub
+}
+
+fn validation(algorithm: Algorithm, leeway: u64) -> Validation {
+    let mut validation = Validation::new(algorithm.into());
     validation.validate_aud = false;
-    validation.leeway = 0;
+    validation.validate_nbf = true;
+    validation.leeway = leeway;
+    validation
+}
+
+fn decode_claims(token: &str, key: &[u8], algorithm: Algorithm, validation: &Validation) -> Option<Claims> {
+    let key = match algorithm.decoding_key(key) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("verify jwt failed, invalid key {e:?}");
+            return None;
+        }
+    };
     match jsonwebtoken::decode::<Claims>(
         token, 
-        &DecodingKey::from_secret(key), 
-        &validation
+        &key, 
+        validation
     ){
         Ok(v) => {
-            v.claims.sub
+            Some(v.claims)
         },
         Err(_) => {
             None
@@ -69,3 +257,108 @@ pub fn verify_token(token: &str, key: &[u8]) -> Option<String> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_PEM: &[u8] = include_bytes!("../testdata/rs256_private.pem");
+    const PUBLIC_PEM: &[u8] = include_bytes!("../testdata/rs256_public.pem");
+
+    #[test]
+    fn test_hs256() {
+        let token = create_token("user1", b"secret");
+        assert_eq!(verify_token(&token, b"secret").as_deref(), Some("user1"));
+        assert_eq!(verify_token(&token, b"other"), None);
+    }
+
+    #[test]
+    fn test_rs256() {
+        let token = create_token_rs256("user1", PRIVATE_PEM);
+        assert!(!token.is_empty());
+        assert_eq!(verify_token_rs256(&token, PUBLIC_PEM).as_deref(), Some("user1"));
+
+        // the algorithm of the token has to match the expected one
+        assert_eq!(verify_token(&token, PUBLIC_PEM), None);
+        let token = create_token("user1", PUBLIC_PEM);
+        assert_eq!(verify_token_rs256(&token, PUBLIC_PEM), None);
+    }
+
+    #[test]
+    fn test_custom_claims() {
+        let mut extra = Map::new();
+        extra.insert("roles".to_string(), serde_json::json!(["admin", "editor"]));
+        extra.insert("tenant_id".to_string(), serde_json::json!(7));
+        extra.insert("sub".to_string(), serde_json::json!("intruder"));
+        let token = create_token_with_claims("user1", b"secret", extra);
+
+        let claims = verify_claims(&token, b"secret", Algorithm::HS256).unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("user1"));
+        assert_eq!(claims.extra["roles"], serde_json::json!(["admin", "editor"]));
+        assert_eq!(claims.extra["tenant_id"], serde_json::json!(7));
+        assert!(!claims.extra.contains_key("sub"));
+
+        assert_eq!(verify_token(&token, b"secret").as_deref(), Some("user1"));
+    }
+
+    #[test]
+    fn test_refresh_token() {
+        let refresh = create_refresh_token("user1", b"secret");
+        let claims = decode_claims(&refresh, b"secret", Algorithm::HS256, &validation(Algorithm::HS256, 0)).unwrap();
+        assert_eq!(claims.typ.as_deref(), Some(REFRESH_TOKEN_TYPE));
+        assert_eq!(verify_refresh_token(&refresh, b"secret").as_deref(), Some("user1"));
+        assert_eq!(verify_refresh_token(&refresh, b"other"), None);
+        // a refresh token is no access token, and the other way around
+        assert_eq!(verify_token(&refresh, b"secret"), None);
+        let access = create_token("user1", b"secret");
+        assert_eq!(verify_refresh_token(&access, b"secret"), None);
+
+        let access = refresh_access_token(&refresh, b"secret").unwrap();
+        assert_eq!(verify_token(&access, b"secret").as_deref(), Some("user1"));
+        assert_eq!(refresh_access_token(&access, b"secret"), None);
+    }
+
+    #[test]
+    fn test_leeway() {
+        // expired 2 seconds ago
+        let claims = Claims::new("user1", -2);
+        let token = encode_claims(&claims, b"secret", Algorithm::HS256);
+        assert_eq!(verify_token_with_leeway(&token, b"secret", 5).as_deref(), Some("user1"));
+        assert_eq!(verify_token_with_leeway(&token, b"secret", 0), None);
+
+        // not valid before 2 seconds from now
+        let mut claims = Claims::new("user1", 60);
+        claims.nbf = Some(claims.exp - 58);
+        let token = encode_claims(&claims, b"secret", Algorithm::HS256);
+        assert_eq!(verify_token_with_leeway(&token, b"secret", 5).as_deref(), Some("user1"));
+        assert_eq!(verify_token_with_leeway(&token, b"secret", 0), None);
+    }
+
+    #[test]
+    fn test_audience_and_issuer() {
+        let token = create_token_for("user1", b"secret", "orders", "auth");
+        assert_eq!(verify_token_for(&token, b"secret", Some("orders"), Some("auth")).as_deref(), Some("user1"));
+        assert_eq!(verify_token_for(&token, b"secret", Some("orders"), None).as_deref(), Some("user1"));
+        assert_eq!(verify_token_for(&token, b"secret", Some("billing"), Some("auth")), None);
+        assert_eq!(verify_token_for(&token, b"secret", Some("orders"), Some("other")), None);
+        // the claims are not checked unless expected
+        assert_eq!(verify_token(&token, b"secret").as_deref(), Some("user1"));
+
+        // a token without the expected claims is rejected
+        let token = create_token("user1", b"secret");
+        assert_eq!(verify_token_for(&token, b"secret", Some("orders"), None), None);
+        assert_eq!(verify_token_for(&token, b"secret", None, Some("auth")), None);
+        assert_eq!(verify_token_for(&token, b"secret", None, None).as_deref(), Some("user1"));
+    }
+
+    #[test]
+    fn test_multi_key() {
+        let keys: [&[u8]; 2] = [b"active", b"retired"];
+        let token = create_token("user1", b"retired");
+        assert_eq!(verify_token_multi(&token, &keys).as_deref(), Some("user1"));
+        let token = create_token("user2", b"active");
+        assert_eq!(verify_token_multi(&token, &keys).as_deref(), Some("user2"));
+        let token = create_token("user3", b"other");
+        assert_eq!(verify_token_multi(&token, &keys), None);
+        assert_eq!(verify_token_multi(&token, &[]), None);
+    }
+}
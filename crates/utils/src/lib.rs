@@ -1,4 +1,4 @@
-use chrono::{Datelike, TimeZone};
+use chrono::TimeZone;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use crate::vars::get_env_var;
 pub use zenoh;
@@ -8,106 +8,113 @@ pub mod xid;
 pub mod jwt;
 pub mod snowflake;
 pub mod zenoh_zession;
+pub mod rate_limit;
+pub mod outbox;
 
 pub const EXIT_OK: i32 = 0;
 pub const EXIT_START_NODE_ERROR: i32 = 10;
+pub const EXIT_BIND_ERROR: i32 = 11;
+pub const EXIT_SERVE_ERROR: i32 = 12;
+pub const EXIT_CONFIG_ERROR: i32 = 13;
 
 pub fn get_tz() -> String {
     get_env_var("SERVICE_TZ", "Asia/Tokyo".to_string())
 }
 
-/// Get the UNIX timestamp (in seconds) for the start of "today"
-/// in the time zone specified by the `TZ` environment variable.
-/// Defaults to Asia/Tokyo if the environment variable is not set.
-pub fn start_of_today() -> i64 {
-    // Read the time zone name from the "TZ" environment variable
-    // Use "Asia/Tokyo" as the default if not set
-    let tz_name = get_tz();
-
-    // Parse the time zone name into a `chrono_tz::Tz` type
-    let tz: chrono_tz::Tz = tz_name.parse().unwrap_or(chrono_tz::Tz::Asia__Tokyo);
+/// Default format of the local datetime strings
+pub const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// Default format of the local date strings
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
 
-    // Get the current time in UTC
-    let now_utc =  chrono::Utc::now();
-
-    // Convert UTC time to the specified time zone
-    let now_in_tz = now_utc.with_timezone(&tz);
-
-    // Extract the year/month/day in the target time zone (without time)
-    let date = now_in_tz.date_naive();
+fn get_service_tz() -> chrono_tz::Tz {
+    // Read the time zone name from the "SERVICE_TZ" environment variable
+    // Use "Asia/Tokyo" as the default if not set or invalid
+    get_tz().parse().unwrap_or(chrono_tz::Tz::Asia__Tokyo)
+}
 
-    // Build the DateTime at 00:00:00 (start of day) in the specified time zone
-    match tz.with_ymd_and_hms(date.year(), date.month(), date.day(), 0, 0, 0){
-        chrono::offset::LocalResult::Single(v) => v.timestamp(),
-        chrono::offset::LocalResult::Ambiguous(_, v2) => v2.timestamp(),
-        chrono::offset::LocalResult::None => chrono::Local::now().timestamp(),
+/// Resolves a local wall clock time of the time zone to an instant
+/// - Ambiguous times, repeated when the clocks fall back, resolve to the earlier offset
+/// - Times skipped when the clocks spring forward resolve to the first instant after the gap
+fn resolve_local(tz: &chrono_tz::Tz, local: &chrono::NaiveDateTime) -> Option<chrono::DateTime<chrono_tz::Tz>> {
+    // transitions skip at most a day, probed at the 15 minute granularity of the offsets
+    for step in 0..=100 {
+        let probe = *local + chrono::Duration::minutes(15 * step);
+        match tz.from_local_datetime(&probe) {
+            chrono::offset::LocalResult::Single(v) => return Some(v),
+            chrono::offset::LocalResult::Ambiguous(earlier, _) => return Some(earlier),
+            chrono::offset::LocalResult::None => continue,
+        }
     }
+    None
 }
 
+fn start_of_day(tz: &chrono_tz::Tz, date: chrono::NaiveDate) -> i64 {
+    // Build the DateTime at 00:00:00 (start of day) in the specified time zone,
+    // where midnight is skipped by DST the day starts right after the gap
+    resolve_local(tz, &date.and_time(chrono::NaiveTime::MIN))
+        .map(|v| v.timestamp())
+        .unwrap_or_default()
+}
 
-/// Get the datetime string from a timestamp
-/// in the time zone specified by the `TZ` environment variable.
-/// Defaults to Asia/Tokyo if the environment variable is not set.
-pub fn get_local_datetime_formarted(timestamp: i64) -> String {
-    // Read the time zone name from the "TZ" environment variable
-    // Use "Asia/Tokyo" as the default if not set
-    let tz_name = get_tz();
+/// Get the UNIX timestamp (in seconds) for the start of "today" in the time zone.
+/// On days where midnight is skipped by DST, the day starts at the end of the gap.
+pub fn start_of_today_in(tz: chrono_tz::Tz) -> i64 {
+    let today = chrono::Utc::now().with_timezone(&tz).date_naive();
+    start_of_day(&tz, today)
+}
 
-    // Parse the time zone name into a `chrono_tz::Tz` type
-    let tz: chrono_tz::Tz = tz_name.parse().unwrap_or(chrono_tz::Tz::Asia__Tokyo);
+/// Format a timestamp with the `chrono` format string in the time zone.
+pub fn format_local_in(tz: chrono_tz::Tz, timestamp: i64, fmt: &str) -> String {
+    let utc = chrono::DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
+    utc.with_timezone(&tz).format(fmt).to_string()
+}
 
-    // Get the current time in UTC
-    let now_utc =  chrono::DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
+/// Parse a local datetime string of the time zone, ambiguous and skipped times are resolved
+/// like `start_of_today_in`, returns 0 if the string is invalid.
+pub fn get_timestamp_from_local_in(tz: chrono_tz::Tz, datetime: &str, fmt: &str) -> i64 {
+    let local = match chrono::NaiveDateTime::parse_from_str(datetime, fmt){
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("{}:{} failed: {e:?}", file!(), line!());
+            return 0;
+        },
+    };
+    resolve_local(&tz, &local)
+        .map(|v| v.timestamp())
+        .unwrap_or_default()
+}
 
-    // Convert UTC time to the specified time zone
-    let now_in_tz = now_utc.with_timezone(&tz);
+/// Get the UNIX timestamp (in seconds) for the start of "today"
+/// in the time zone specified by the `SERVICE_TZ` environment variable.
+/// Defaults to Asia/Tokyo if the environment variable is not set.
+pub fn start_of_today() -> i64 {
+    start_of_today_in(get_service_tz())
+}
 
-    // Extract the year/month/day in the target time zone (without time)
-    format!("{}", now_in_tz.format("%Y-%m-%d %H:%M:%S"))
+/// Format a timestamp with the `chrono` format string
+/// in the time zone specified by the `SERVICE_TZ` environment variable.
+pub fn format_local(timestamp: i64, fmt: &str) -> String {
+    format_local_in(get_service_tz(), timestamp, fmt)
 }
 
 /// Get the datetime string from a timestamp
-/// in the time zone specified by the `TZ` environment variable.
+/// in the time zone specified by the `SERVICE_TZ` environment variable.
 /// Defaults to Asia/Tokyo if the environment variable is not set.
-pub fn get_local_date_formarted(timestamp: i64) -> String {
-    // Read the time zone name from the "TZ" environment variable
-    // Use "Asia/Tokyo" as the default if not set
-    let tz_name = get_tz();
-
-    // Parse the time zone name into a `chrono_tz::Tz` type
-    let tz: chrono_tz::Tz = tz_name.parse().unwrap_or(chrono_tz::Tz::Asia__Tokyo);
-
-    // Get the current time in UTC
-    let now_utc =  chrono::DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
-
-    // Convert UTC time to the specified time zone
-    let now_in_tz = now_utc.with_timezone(&tz);
+pub fn get_local_datetime_formarted(timestamp: i64) -> String {
+    format_local(timestamp, DATETIME_FORMAT)
+}
 
-    // Extract the year/month/day in the target time zone (without time)
-    format!("{}", now_in_tz.format("%Y-%m-%d"))
+/// Get the date string from a timestamp
+/// in the time zone specified by the `SERVICE_TZ` environment variable.
+/// Defaults to Asia/Tokyo if the environment variable is not set.
+pub fn get_local_date_formarted(timestamp: i64) -> String {
+    format_local(timestamp, DATE_FORMAT)
 }
 
+/// Parse a local datetime string of the time zone specified by the `SERVICE_TZ` environment variable.
+/// Ambiguous and skipped times are resolved like `start_of_today`, returns 0 if the string is invalid.
 pub fn get_timestamp_from_local(datetime: &str, fmt: &str) -> i64 {
-    // Read the time zone name from the "TZ" environment variable
-    // Use "Asia/Tokyo" as the default if not set
-    let tz_name = get_tz();
-
-    // Parse the time zone name into a `chrono_tz::Tz` type
-    let tz: chrono_tz::Tz = tz_name.parse().unwrap_or(chrono_tz::Tz::Asia__Tokyo);
-   
-
-    let local = match chrono::NaiveDateTime::parse_from_str(datetime, fmt){
-        Ok(v) => v,
-        Err(e) => {
-            tracing::error!("{}:{} failed: {e:?}", file!(), line!());
-            return 0;
-        },
-    };
-    match tz.from_local_datetime(&local){
-        chrono::offset::LocalResult::Single(v) => v.timestamp(),
-        chrono::offset::LocalResult::Ambiguous(_, v2) => v2.timestamp(),
-        chrono::offset::LocalResult::None => 0,
-    }
+    get_timestamp_from_local_in(get_service_tz(), datetime, fmt)
 }
 
 pub fn get_timestamp_from_utc(datetime: &str, fmt: &str) -> i64 {
@@ -158,7 +165,45 @@ pub async fn shutdown_signal() {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn naive(datetime: &str) -> chrono::NaiveDateTime {
+        chrono::NaiveDateTime::parse_from_str(datetime, DATETIME_FORMAT).unwrap()
+    }
+
+    #[test]
+    fn test_dst_boundaries() {
+        let tz = chrono_tz::America::New_York;
+        // spring forward: 02:30 does not exist, resolves to 03:00 EDT
+        let skipped = resolve_local(&tz, &naive("2024-03-10 02:30:00")).unwrap();
+        assert_eq!(skipped.timestamp(), 1_710_054_000);
+        // fall back: 01:30 happens twice, resolves to the earlier EDT one
+        let repeated = resolve_local(&tz, &naive("2024-11-03 01:30:00")).unwrap();
+        assert_eq!(repeated.timestamp(), 1_730_611_800);
+        assert_eq!(format_local_in(tz, repeated.timestamp(), "%H:%M %Z"), "01:30 EDT");
+        assert_eq!(get_timestamp_from_local_in(tz, "2024-11-03 01:30:00", DATETIME_FORMAT), 1_730_611_800);
+
+        // the day starts at 01:00 where midnight is skipped
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        assert_eq!(start_of_day(&chrono_tz::America::Havana, date), 1_710_046_800);
+        assert_eq!(start_of_day(&tz, date), 1_710_046_800);
+    }
 
+    #[test]
+    fn test_format_local_in() {
+        assert_eq!(format_local_in(chrono_tz::Asia::Tokyo, 0, DATETIME_FORMAT), "1970-01-01 09:00:00");
+        assert_eq!(format_local_in(chrono_tz::Asia::Tokyo, 0, "%d/%m/%Y"), "01/01/1970");
+        assert_eq!(format_local_in(chrono_tz::Europe::London, 0, DATETIME_FORMAT), "1970-01-01 01:00:00");
+    }
 
-        
+    #[test]
+    fn test_start_of_today_in() {
+        for tz in [chrono_tz::Asia::Tokyo, chrono_tz::America::New_York] {
+            let start = start_of_today_in(tz);
+            assert_eq!(format_local_in(tz, start, "%H:%M:%S"), "00:00:00");
+            assert!(chrono::Utc::now().timestamp() - start < 24 * 3600);
+        }
+    }
+}
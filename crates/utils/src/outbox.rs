@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+/// What a full `Outbox` does with a new message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drops the oldest queued message, for streams where only recent messages matter, e.g. telemetry
+    #[default]
+    DropOldest,
+    /// Closes the outbox, for reliable streams where a gap is worse than reconnecting
+    Close,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop_oldest" | "" => Ok(OverflowPolicy::DropOldest),
+            "close" => Ok(OverflowPolicy::Close),
+            _ => Err(format!("unknown overflow policy {s}")),
+        }
+    }
+}
+
+/// The outbox is closed, nothing more is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+struct State<T> {
+    queue: VecDeque<T>,
+    closed: bool,
+    dropped: u64,
+}
+
+/// Bounded queue between many producers and one consumer, where producers never wait for a slow consumer
+/// Once `capacity` messages are queued, the `OverflowPolicy` decides what happens to a new one
+pub struct Outbox<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl<T> Outbox<T> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            state: Mutex::new(State { queue: VecDeque::new(), closed: false, dropped: 0 }),
+            notify: Notify::new(),
+            capacity: capacity.max(1),
+            policy,
+        }
+    }
+
+    /// Queues the message without waiting, fails once the outbox is closed, by `close` or by an overflow
+    pub fn push(&self, value: T) -> Result<(), Closed> {
+        let mut state = self.state.lock();
+        if state.closed {
+            return Err(Closed);
+        }
+        if state.queue.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                    state.dropped += 1;
+                }
+                OverflowPolicy::Close => {
+                    state.closed = true;
+                    state.queue.clear();
+                    drop(state);
+                    self.notify.notify_one();
+                    return Err(Closed);
+                }
+            }
+        }
+        state.queue.push_back(value);
+        drop(state);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Waits for the next message, none once the outbox is closed
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock();
+                if state.closed {
+                    return None;
+                }
+                if let Some(value) = state.queue.pop_front() {
+                    return Some(value);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Stops delivering, queued messages are discarded
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        state.queue.clear();
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+
+    /// Number of messages dropped by `OverflowPolicy::DropOldest`
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    /// Pushes faster than a reader taking 10ms per message, returns what the reader got
+    async fn slow_reader(policy: OverflowPolicy) -> (Arc<Outbox<u32>>, Vec<u32>, usize) {
+        let outbox = Arc::new(Outbox::new(4, policy));
+        let reader = tokio::spawn({
+            let outbox = outbox.clone();
+            async move {
+                let mut received = Vec::new();
+                while let Some(value) = outbox.pop().await {
+                    received.push(value);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                received
+            }
+        });
+        let mut pushed = 0;
+        for value in 0..100 {
+            if outbox.push(value).is_err() {
+                break;
+            }
+            pushed += 1;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        outbox.close();
+        (outbox.clone(), reader.await.unwrap(), pushed)
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let (outbox, received, pushed) = slow_reader(OverflowPolicy::DropOldest).await;
+        // the producer never waited, the reader got the latest messages and skipped older ones
+        assert_eq!(pushed, 100);
+        assert!(outbox.dropped() > 0);
+        assert_eq!(received.last(), Some(&99));
+        assert!(received.windows(2).all(|v| v[0] < v[1]));
+        assert_eq!(received.len() as u64 + outbox.dropped(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_close_on_overflow() {
+        let (outbox, received, pushed) = slow_reader(OverflowPolicy::Close).await;
+        assert!(pushed < 100);
+        assert!(outbox.is_closed());
+        assert_eq!(outbox.dropped(), 0);
+        // nothing is skipped before the outbox closes
+        assert_eq!(received, (0..received.len() as u32).collect::<Vec<_>>());
+        assert_eq!(outbox.push(0), Err(Closed));
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("drop_oldest".parse::<OverflowPolicy>().unwrap(), OverflowPolicy::DropOldest);
+        assert_eq!("CLOSE".parse::<OverflowPolicy>().unwrap(), OverflowPolicy::Close);
+        assert!("block".parse::<OverflowPolicy>().is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+
+// Interval between two sweeps of the idle buckets
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter keyed by an arbitrary string, e.g. the client ip
+/// Every key starts with `capacity` tokens, a request takes one token and tokens are refilled at `refill` per second
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill: f64,
+    buckets: DashMap<String, Bucket>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, refill: f64) -> Self {
+        Self {
+            capacity: capacity.max(1) as f64,
+            refill: refill.max(f64::MIN_POSITIVE),
+            buckets: DashMap::new(),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Takes a token for the key, returns how long to wait for the next one when the bucket is empty
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    /// Number of keys currently tracked
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        self.sweep(now);
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(&bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            // a refill of 0 never gives the token back, the wait saturates instead of overflowing
+            Err(Duration::try_from_secs_f64((1.0 - bucket.tokens) / self.refill).unwrap_or(Duration::MAX))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.refill).min(self.capacity)
+    }
+
+    /// Drops the buckets that are full again, they behave the same as a new bucket
+    fn sweep(&self, now: Instant) {
+        {
+            let mut last_sweep = self.last_sweep.lock();
+            if now.saturating_duration_since(*last_sweep) < SWEEP_INTERVAL {
+                return;
+            }
+            *last_sweep = now;
+        }
+        self.buckets.retain(|_, bucket| self.refilled(bucket, now) < self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2, 1.0);
+        let now = Instant::now();
+        assert!(limiter.check_at("a", now).is_ok());
+        assert!(limiter.check_at("a", now).is_ok());
+        assert_eq!(limiter.check_at("a", now), Err(Duration::from_secs(1)));
+        // other keys have their own bucket
+        assert!(limiter.check_at("b", now).is_ok());
+
+        let now = now + Duration::from_millis(500);
+        assert_eq!(limiter.check_at("a", now), Err(Duration::from_millis(500)));
+        let now = now + Duration::from_millis(500);
+        assert!(limiter.check_at("a", now).is_ok());
+
+        // idle buckets are evicted once refilled
+        let now = now + SWEEP_INTERVAL;
+        assert!(limiter.check_at("c", now).is_ok());
+        assert_eq!(limiter.len(), 1);
+    }
+
+    #[test]
+    fn test_no_refill() {
+        let limiter = RateLimiter::new(1, 0.0);
+        let now = Instant::now();
+        assert!(limiter.check_at("a", now).is_ok());
+        assert_eq!(limiter.check_at("a", now), Err(Duration::MAX));
+        assert_eq!(limiter.check_at("a", now + SWEEP_INTERVAL), Err(Duration::MAX));
+    }
+}
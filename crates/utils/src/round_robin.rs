@@ -1,5 +1,5 @@
 use std::{
-    collections::BTreeSet, 
+    collections::{BTreeMap, BTreeSet, HashMap}, 
     ops::{Deref, DerefMut}, 
     sync::{
         atomic::{AtomicUsize, Ordering}, 
@@ -12,6 +12,8 @@ use dashmap::DashMap;
 struct RoundRobinSet<T> {
     inner: BTreeSet<T>,
     counter: AtomicUsize,
+    // Weights of members inserted with `insert_weighted`, other members weigh 1
+    weights: BTreeMap<T, u32>,
 }
 
 impl<T> Default for RoundRobinSet<T> 
@@ -21,7 +23,8 @@ where
     fn default() -> Self {
         Self { 
             inner: Default::default(), 
-            counter: Default::default() 
+            counter: Default::default(),
+            weights: Default::default(),
         }
     }
 }
@@ -63,11 +66,51 @@ where
         self.inner.iter().nth(index).cloned()
     }
 
+    /// Picks up to `n` distinct members, the next ones in round robin order
+    /// The counter advances by the number of members picked, so consecutive calls spread over the set
+    fn next_n(&self, n: usize) -> Vec<T> {
+        let count = n.min(self.inner.len());
+        if count == 0 {
+            return Vec::new();
+        }
+        let start = self.counter.fetch_add(count, Ordering::Relaxed) % self.inner.len();
+        self.inner.iter().cycle().skip(start).take(count).cloned().collect()
+    }
+
+    /// Picks members proportionally to their weight
+    /// The counter walks the cumulative weights, so no member list expanded by weight is needed
+    fn next_weighted(&self) -> Option<T> {
+        let total: u64 = self.inner.iter().map(|value| u64::from(self.weight(value))).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let current = self.counter.fetch_add(1, Ordering::Relaxed) as u64;
+        let mut index = current % total;
+        for value in self.inner.iter() {
+            let weight = u64::from(self.weight(value));
+            if index < weight {
+                return Some(value.clone());
+            }
+            index -= weight;
+        }
+        None
+    }
+
+    fn weight(&self, value: &T) -> u32 {
+        self.weights.get(value).copied().unwrap_or(1)
+    }
+
     // Create a new RoundRobinSet from BTreeSet
     fn from_set(set: BTreeSet<T>) -> Self {
+        Self::from_parts(set, BTreeMap::new())
+    }
+
+    fn from_parts(set: BTreeSet<T>, weights: BTreeMap<T, u32>) -> Self {
         Self {
             inner: set,
             counter: AtomicUsize::new(0),
+            weights,
         }
     }
 }
@@ -82,51 +125,81 @@ where
     T: Clone + std::cmp::Eq + std::cmp::Ord + Send + Sync + 'static
 {
     pub fn insert(&self, key: String, value: T) {
+        self.upsert(key, value, None);
+    }
+
+    /// Inserts a member that `get_weighted` selects proportionally to `weight`
+    /// A weight of 0 keeps the member registered but never selected by `get_weighted`
+    pub fn insert_weighted(&self, key: String, value: T, weight: u32) {
+        self.upsert(key, value, Some(weight));
+    }
+
+    // Inserts the value, updating its weight when given and keeping the current one otherwise
+    fn upsert(&self, key: String, value: T, weight: Option<u32>) {
         self.inner
             .entry(key)
             .and_modify(|entry| {
                 // Clone value here since we need it in multiple places
                 let value = value.clone();
                 if let Some(mut_entry) = Arc::get_mut(entry) {
+                    if let Some(weight) = weight {
+                        mut_entry.weights.insert(value.clone(), weight);
+                    }
                     mut_entry.inner.insert(value);
                 } else {
                     // If there are multiple references, create a new set with existing values
                     let mut new_set = entry.inner.clone();
+                    let mut weights = entry.weights.clone();
+                    if let Some(weight) = weight {
+                        weights.insert(value.clone(), weight);
+                    }
                     new_set.insert(value);
-                    *entry = Arc::new(RoundRobinSet {
-                        inner: new_set,
-                        counter: AtomicUsize::new(0),
-                    });
+                    *entry = Arc::new(RoundRobinSet::from_parts(new_set, weights));
                 }
             })
             .or_insert_with(|| {
                 // If key doesn't exist, create a new set containing only the new value
                 // This avoids unnecessary allocations and cloning
                 let mut set = BTreeSet::new();
+                let mut weights = BTreeMap::new();
+                if let Some(weight) = weight {
+                    weights.insert(value.clone(), weight);
+                }
                 set.insert(value);
-                Arc::new(RoundRobinSet::from_set(set))
+                Arc::new(RoundRobinSet::from_parts(set, weights))
             });
     }
 
+    /// Removes the member, the key is dropped along with its last member
     pub fn remove(&self, key: String, value: T) -> bool {
-        if let Some(mut entry) = self.inner.get_mut(&key) {
+        let removed = if let Some(mut entry) = self.inner.get_mut(&key) {
             if let Some(round_robin) = Arc::get_mut(entry.value_mut()) {
+                round_robin.weights.remove(&value);
                 round_robin.inner.remove(&value)
             } else {
                 // If there are multiple references, create new set
                 let mut new_set = entry.inner.clone();
                 let removed = new_set.remove(&value);
                 if removed {
-                    *entry.value_mut() = Arc::new(RoundRobinSet {
-                        inner: new_set,
-                        counter: AtomicUsize::new(0),
-                    });
+                    let mut weights = entry.weights.clone();
+                    weights.remove(&value);
+                    *entry.value_mut() = Arc::new(RoundRobinSet::from_parts(new_set, weights));
                 }
                 removed
             }
         } else {
             false
+        };
+        if removed {
+            // checked again under the lock of the entry, a concurrent insert keeps the key
+            self.inner.remove_if(&key, |_, entry| entry.is_empty());
         }
+        removed
+    }
+
+    /// Drops every key without members, e.g. left behind by `update` with an empty set
+    pub fn prune_empty(&self) {
+        self.inner.retain(|_, entry| !entry.is_empty());
     }
 
     pub fn get_round_robin(&self, key: &str) -> Option<T> {
@@ -134,6 +207,33 @@ where
         entry.next()
     }
 
+    /// Returns up to `n` distinct members in round robin order, all of them when the key has fewer
+    pub fn get_round_robin_n(&self, key: &str, n: usize) -> Vec<T> {
+        self.inner.get(key).map(|entry| entry.next_n(n)).unwrap_or_default()
+    }
+
+    /// Selects a member proportionally to its weight, see `insert_weighted`
+    pub fn get_weighted(&self, key: &str) -> Option<T> {
+        let entry = self.inner.get(key)?;
+        entry.next_weighted()
+    }
+
+    /// Returns all members of the key without advancing the round robin counter
+    pub fn get_all(&self, key: &str) -> Vec<T> {
+        self.inner
+            .get(key)
+            .map(|entry| entry.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Copies the members of every key without advancing the round robin counters
+    pub fn snapshot(&self) -> HashMap<String, Vec<T>> {
+        self.inner
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.iter().cloned().collect()))
+            .collect()
+    }
+
     pub fn update(&self, key: &str, new_set: BTreeSet<T>) -> bool {
         self.inner.insert(key.to_string(), Arc::new(RoundRobinSet::from_set(new_set)));
         true
@@ -175,4 +275,99 @@ mod tests {
         assert!(second.is_some());
         assert_ne!(first, second);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_get_all() {
+        let map = RoundRobinDashMap::<String>::default();
+        assert!(map.get_all("test").is_empty());
+
+        map.insert("test".to_string(), "node2".to_string());
+        map.insert("test".to_string(), "node1".to_string());
+        assert_eq!(map.get_all("test"), vec!["node1".to_string(), "node2".to_string()]);
+
+        // reading all members must not move the round robin position
+        let first = map.get_round_robin("test");
+        map.get_all("test");
+        let second = map.get_round_robin("test");
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_get_round_robin_n() {
+        let map = RoundRobinDashMap::<u32>::default();
+        assert!(map.get_round_robin_n("test", 2).is_empty());
+        for node in 1..=3 {
+            map.insert("test".to_string(), node);
+        }
+        assert_eq!(map.get_round_robin_n("test", 2), vec![1, 2]);
+        // picks distinct members, wrapping around the set
+        assert_eq!(map.get_round_robin_n("test", 2), vec![3, 1]);
+        assert_eq!(map.get_round_robin("test"), Some(2));
+        // all members when there are fewer than asked
+        let mut all = map.get_round_robin_n("test", 5);
+        all.sort();
+        assert_eq!(all, vec![1, 2, 3]);
+        assert!(map.get_round_robin_n("test", 0).is_empty());
+    }
+
+    #[test]
+    fn test_weighted() {
+        let map = RoundRobinDashMap::<String>::default();
+        map.insert_weighted("test".to_string(), "big".to_string(), 3);
+        map.insert("test".to_string(), "small".to_string());
+        map.insert_weighted("test".to_string(), "drained".to_string(), 0);
+
+        let mut big = 0;
+        let mut small = 0;
+        for _ in 0..400 {
+            match map.get_weighted("test").as_deref() {
+                Some("big") => big += 1,
+                Some("small") => small += 1,
+                other => panic!("unexpected selection {other:?}"),
+            }
+        }
+        assert_eq!(big, 300);
+        assert_eq!(small, 100);
+
+        // a plain insert keeps the existing weight
+        map.insert("test".to_string(), "big".to_string());
+        let selected: Vec<_> = (0..4).filter_map(|_| map.get_weighted("test")).collect();
+        assert_eq!(selected.iter().filter(|v| *v == "big").count(), 3);
+
+        assert!(map.remove("test".to_string(), "big".to_string()));
+        assert_eq!(map.get_weighted("test").as_deref(), Some("small"));
+        assert_eq!(map.get_weighted("unknown"), None);
+    }
+
+    #[test]
+    fn test_remove_last_member() {
+        let map = RoundRobinDashMap::<String>::default();
+        map.insert("test".to_string(), "node1".to_string());
+        assert!(map.remove("test".to_string(), "node1".to_string()));
+        assert!(!map.contains_key("test"));
+        assert_eq!(map.get_round_robin("test"), None);
+        assert!(map.keys().is_empty());
+        assert!(!map.remove("test".to_string(), "node1".to_string()));
+
+        map.update("empty", BTreeSet::new());
+        map.insert("test".to_string(), "node1".to_string());
+        assert_eq!(map.len(), 2);
+        map.prune_empty();
+        assert_eq!(map.keys(), vec!["test".to_string()]);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let map = RoundRobinDashMap::<String>::default();
+        map.insert("a".to_string(), "node2".to_string());
+        map.insert("a".to_string(), "node1".to_string());
+        map.insert("b".to_string(), "node3".to_string());
+
+        let first = map.get_round_robin("a");
+        let snapshot = map.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot["a"], vec!["node1".to_string(), "node2".to_string()]);
+        assert_eq!(snapshot["b"], vec!["node3".to_string()]);
+        assert_ne!(map.get_round_robin("a"), first);
+    }
+}
@@ -18,22 +18,59 @@ const ALPHABET33: [u8; 33] = [
 
 const WORKER_ID_BITS: i64 = 10;
 const SEQUENCE_BITS: i64 = 12;
-const TIMESTAMP_BITS: i64 = 41;
 
 // use bit operations to get max number of the item
-const MAX_WORKER_ID: i64 = -1 ^ (-1 << WORKER_ID_BITS);
+pub const MAX_WORKER_ID: i64 = -1 ^ (-1 << WORKER_ID_BITS);
 
-// shift bits
-const WORKER_ID_SHIFT: i64 = SEQUENCE_BITS;
-const TIMESTAMP_LEFT_SHIFT: i64 = SEQUENCE_BITS + WORKER_ID_BITS;
-
-// masks
-const SEQUENCE_MASK:i64 = -1 ^ (-1 << SEQUENCE_BITS);
 const EPOCH: i64 = 1_730_203_481_000;
 
 
+/// Bit layout of the generated ids
+/// The timestamp takes the remaining `63 - worker_id_bits - sequence_bits` bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnowflakeConfig {
+    /// Unix time in milliseconds the id timestamps are relative to
+    pub epoch: i64,
+    pub worker_id_bits: i64,
+    pub sequence_bits: i64,
+}
+
+impl Default for SnowflakeConfig {
+    fn default() -> Self {
+        Self {
+            epoch: EPOCH,
+            worker_id_bits: WORKER_ID_BITS,
+            sequence_bits: SEQUENCE_BITS,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ParseIdError {
+    #[error("Empty id")]
+    Empty,
+    #[error("Invalid character '{0}' at position {1}")]
+    InvalidCharacter(char, usize),
+    #[error("Id does not fit in 64 bits")]
+    Overflow,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnowflakeError {
+    #[error("Invalid bit layout: worker_id_bits ({0}) + sequence_bits ({1}) must be at most {max}", max = WORKER_ID_BITS + SEQUENCE_BITS)]
+    InvalidLayout(i64, i64),
+    /// The worker id and the maximum of the layout
+    #[error("Worker id {0} out of range 0..={1}")]
+    WorkerIdOutOfRange(i64, i64),
+}
+
 pub struct Snowflake {
     worker_id: i64,
+    config: SnowflakeConfig,
+    timestamp_bits: i64,
+    worker_id_shift: i64,
+    timestamp_left_shift: i64,
+    sequence_mask: i64,
     // Use Mutex to protect sequence and last_timestamp
     inner: Mutex<SnowflakeInner>,
 }
@@ -49,20 +86,62 @@ impl Snowflake {
         let worker_id: i64 = if let Some(v) = crate::vars::get_server_id(){
             v
         } else {
-            // If not exists, use the last segment of IP address as worker_id
-            let ip = get_ip();
-            let ip_split: Vec<&str> = ip.split(".").collect();
-            // Use 16bits ip address as worker_id
-            (ip_split[2].to_string().parse::<i64>().unwrap() << 8) | (ip_split[3].to_string().parse::<i64>().unwrap())
+            // If not exists, derive the worker_id from the pod IP address
+            worker_id_from_ip(&get_ip())
         };
+        if !(0..=MAX_WORKER_ID).contains(&worker_id) {
+            tracing::warn!(
+                "{}:{} worker id {worker_id} out of range 0..={MAX_WORKER_ID}, wrapped to {}, it may collide with another node",
+                file!(),
+                line!(),
+                worker_id % (MAX_WORKER_ID + 1)
+            );
+        }
         Snowflake::new(worker_id)
     }
 
+    /// Creates a generator, the worker id wraps modulo `MAX_WORKER_ID + 1`
+    /// so two nodes with e.g. ids 5 and 1029 generate the same ids, prefer `try_new`
     pub fn new(worker_id: i64) -> Self {
-        let worker_id = worker_id % (MAX_WORKER_ID + 1);
+        Self::build(worker_id, SnowflakeConfig::default())
+    }
+
+    /// Creates a generator, rejecting a worker id out of `0..=MAX_WORKER_ID` instead of wrapping it
+    pub fn try_new(worker_id: i64) -> Result<Self, SnowflakeError> {
+        if !(0..=MAX_WORKER_ID).contains(&worker_id) {
+            return Err(SnowflakeError::WorkerIdOutOfRange(worker_id, MAX_WORKER_ID));
+        }
+        Ok(Self::build(worker_id, SnowflakeConfig::default()))
+    }
+
+    /// Creates a generator with a custom epoch and bit layout
+    /// The worker and sequence bits together may not exceed the default 22 bits,
+    /// so the timestamp keeps at least 41 bits. The worker id has to fit in `worker_id_bits`
+    pub fn with_config(worker_id: i64, config: SnowflakeConfig) -> Result<Self, SnowflakeError> {
+        if config.worker_id_bits < 0
+            || config.sequence_bits < 1
+            || config.worker_id_bits + config.sequence_bits > WORKER_ID_BITS + SEQUENCE_BITS
+        {
+            return Err(SnowflakeError::InvalidLayout(config.worker_id_bits, config.sequence_bits));
+        }
+        let max_worker_id = -1 ^ (-1 << config.worker_id_bits);
+        if !(0..=max_worker_id).contains(&worker_id) {
+            return Err(SnowflakeError::WorkerIdOutOfRange(worker_id, max_worker_id));
+        }
+        Ok(Self::build(worker_id, config))
+    }
+
+    fn build(worker_id: i64, config: SnowflakeConfig) -> Self {
+        let max_worker_id = -1 ^ (-1 << config.worker_id_bits);
+        let worker_id = worker_id % (max_worker_id + 1);
         tracing::info!("xid::id::worker_id:{worker_id}");
         Snowflake {
             worker_id,
+            config,
+            timestamp_bits: 63 - config.worker_id_bits - config.sequence_bits,
+            worker_id_shift: config.sequence_bits,
+            timestamp_left_shift: config.sequence_bits + config.worker_id_bits,
+            sequence_mask: -1 ^ (-1 << config.sequence_bits),
             inner: Mutex::new(SnowflakeInner {
                 sequence: 0,
                 last_timestamp: 0,
@@ -70,55 +149,77 @@ impl Snowflake {
         }
     }
 
+    /// Generates the next id, sleeping the thread while the clock is behind the last id or the sequence is exhausted
+    /// Blocks the worker in async code, prefer `next_id_async` there
     pub fn next_id(&self) -> i64 {
+        loop {
+            match self.try_next_id() {
+                Ok(id) => return id,
+                Err(wait) => std::thread::sleep(wait),
+            }
+        }
+    }
+
+    /// Like `next_id`, waiting with `tokio::time::sleep` instead of blocking the thread
+    pub async fn next_id_async(&self) -> i64 {
+        loop {
+            match self.try_next_id() {
+                Ok(id) => return id,
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    /// Id of the current millisecond, or how long to wait before trying again
+    /// when the clock stepped back or the sequence of the millisecond is exhausted
+    fn try_next_id(&self) -> Result<i64, Duration> {
         // Use mutex to protect the entire generation process
         let mut inner = self.inner.lock();
-        
-        let mut timestamp = self.get_time();
-        
-        // Handle clock callback
+        let timestamp = self.get_time();
+
+        // Handle clock callback, wait for the clock to catch up
         if timestamp < inner.last_timestamp {
-            // Wait for clock to catch up
-            while timestamp < inner.last_timestamp {
-                // Release lock to give other threads a chance
-                drop(inner);
-                std::thread::sleep(Duration::from_millis(1));
-                timestamp = self.get_time();
-                inner = self.inner.lock();
-            }
+            return Err(Duration::from_millis(1));
         }
-    
+
         if timestamp == inner.last_timestamp {
             // Within same millisecond, increment sequence
-            inner.sequence = (inner.sequence + 1) & SEQUENCE_MASK;
-            if inner.sequence == 0 {
+            let sequence = (inner.sequence + 1) & self.sequence_mask;
+            if sequence == 0 {
                 // Sequence exhausted, wait for next millisecond
-                timestamp = self.till_next_millis(inner.last_timestamp);
+                return Err(Duration::from_micros(100));
             }
+            inner.sequence = sequence;
         } else {
             // New millisecond, reset sequence
             inner.sequence = 0;
         }
-    
+
         inner.last_timestamp = timestamp;
-    
+
         // Assemble ID
-        _v(timestamp, TIMESTAMP_BITS, TIMESTAMP_LEFT_SHIFT) |
-        _v(self.worker_id, WORKER_ID_BITS, WORKER_ID_SHIFT) | 
-        _v(inner.sequence, SEQUENCE_BITS, 0)
+        Ok(_v(timestamp, self.timestamp_bits, self.timestamp_left_shift) |
+        _v(self.worker_id, self.config.worker_id_bits, self.worker_id_shift) |
+        _v(inner.sequence, self.config.sequence_bits, 0))
     }
 
-    fn till_next_millis(&self, last_timestamp: i64) -> i64 {
-        let mut timestamp = self.get_time();
-        while timestamp <= last_timestamp {
-            std::thread::sleep(Duration::from_micros(100));
-            timestamp = self.get_time();
-        }
-        timestamp
+    /// Unix time in milliseconds the id was generated at, by a generator of this layout
+    pub fn timestamp_of(&self, id: i64) -> i64 {
+        (id >> self.timestamp_left_shift) + self.config.epoch
+    }
+
+    /// Worker id of the generator of the id
+    pub fn worker_id_of(&self, id: i64) -> i64 {
+        (id >> self.worker_id_shift) & (-1 ^ (-1 << self.config.worker_id_bits))
+    }
+
+    /// Position of the id among those generated in the same millisecond
+    pub fn sequence_of(&self, id: i64) -> i64 {
+        id & self.sequence_mask
     }
 
     fn get_time(&self) -> i64 {
-        chrono::Utc::now().timestamp_millis() - EPOCH
+        chrono::Utc::now().timestamp_millis() - self.config.epoch
     }
 }
 
@@ -134,6 +235,28 @@ fn _v(val: i64, n: i64, shift: i64) -> i64 {
 	(val & (pow(2, n) - 1)) << shift
 }
 
+/// Uses the last 16 bits of an IPv4 address as worker id,
+/// the last 16 bits of an IPv6 address folded down to `MAX_WORKER_ID`,
+/// anything else falls back to the hashed machine id
+fn worker_id_from_ip(ip: &str) -> i64 {
+    match ip.trim().parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(v)) => {
+            let octets = v.octets();
+            (i64::from(octets[2]) << 8) | i64::from(octets[3])
+        }
+        Ok(std::net::IpAddr::V6(v)) => {
+            let octets = v.octets();
+            let low = i64::from(u16::from_be_bytes([octets[14], octets[15]]));
+            (low ^ (low >> WORKER_ID_BITS)) & MAX_WORKER_ID
+        }
+        Err(e) => {
+            tracing::warn!("{}:{} invalid POD_IP {ip}: {e}, using the machine id as worker id", file!(), line!());
+            let machine_id = crate::xid::get_machine_id();
+            i64::from(u32::from_be_bytes([0, machine_id[0], machine_id[1], machine_id[2]]))
+        }
+    }
+}
+
 pub fn get_ip() -> String {
     std::env::var("POD_IP").unwrap_or("127.0.0.1".to_owned())
 }
@@ -143,69 +266,106 @@ lazy_static::lazy_static! {
     pub static ref SNOWFLAKE: Snowflake  = Snowflake::k8s();
 }
 
+/// Unix time in milliseconds an id of the default layout was generated at
+pub fn timestamp_of(id: i64) -> i64 {
+    (id >> (WORKER_ID_BITS + SEQUENCE_BITS)) + EPOCH
+}
+
+/// Worker id of the generator of an id of the default layout
+pub fn worker_id_of(id: i64) -> i64 {
+    (id >> SEQUENCE_BITS) & MAX_WORKER_ID
+}
+
+/// Position of an id of the default layout among those generated in the same millisecond
+pub fn sequence_of(id: i64) -> i64 {
+    id & (-1 ^ (-1 << SEQUENCE_BITS))
+}
+
 pub fn generate_id()-> i64 {
     SNOWFLAKE.next_id()
 }
 
+/// Like `generate_id`, without blocking the runtime while waiting for the next millisecond
+pub async fn generate_id_async()-> i64 {
+    SNOWFLAKE.next_id_async().await
+}
+
 pub  fn generate_id_str()-> String {
     to_str(SNOWFLAKE.next_id())
 }
 
 
+// Number of digits needed for the full 64 bits of an id
+const ENCODED_LEN33: usize = 13;
+const ENCODED_LEN57: usize = 11;
+
+/// Parses an id encoded by `to_str`
+/// Falls back to a newly generated id on invalid input, prefer `try_parse_id`
 pub fn parse_id(s: &str)->i64 {
-    // println!("parse_id: {s}");
-    let alpha_len = ALPHABET33.len() as i64;
-    let mut num = 0i64;
-
-    for byte in s.as_bytes() {
-        let opt = ALPHABET33.iter().position(|&c| c == *byte);
-        if opt.is_none() {
-            return generate_id();
-        }
-        let index = opt.unwrap() as i64;
-        num = num * alpha_len + index;
-    }
-    num
+    try_parse_id(s).unwrap_or_else(|e| {
+        tracing::warn!("{}:{} parse id {s} failed: {e}", file!(), line!());
+        generate_id()
+    })
 }
 
+/// Parses an id encoded by `to_str_base57`
+/// Falls back to a newly generated id on invalid input, prefer `try_parse_id_base57`
 pub fn parse_id_base57(s: &str)->i64 {
-    // println!("parse_id_base57: {s}");
-    let alpha_len = ALPHABET57.len() as i64;
-    let mut num = 0i64;
-
-    for byte in s.as_bytes() {
-        let opt = ALPHABET57.iter().position(|&c| c == *byte);
-        if opt.is_none() {
-            return generate_id();
-        }
-        let index = opt.unwrap() as i64;
-        num = num * alpha_len + index;
-    }
-    num
+    try_parse_id_base57(s).unwrap_or_else(|e| {
+        tracing::warn!("{}:{} parse id {s} failed: {e}", file!(), line!());
+        generate_id()
+    })
+}
+
+/// Parses an id encoded by `to_str`
+pub fn try_parse_id(s: &str) -> Result<i64, ParseIdError> {
+    decode(s, &ALPHABET33)
+}
+
+/// Parses an id encoded by `to_str_base57`
+pub fn try_parse_id_base57(s: &str) -> Result<i64, ParseIdError> {
+    decode(s, &ALPHABET57)
 }
 
+/// Encodes the id as a fixed width string of 13 characters, `parse_id` restores it exactly
 pub fn to_str(id: i64) -> String {
-    let mut num = id;
-    let mut bytes = Vec::new();
-    let alpha_len = ALPHABET33.len() as i64;
-    while num > 0 {
-        bytes.push(ALPHABET33[(num % alpha_len) as usize]);
-        num /= alpha_len;
-    }
-    bytes.reverse();
-    String::from_utf8(bytes).unwrap()
+    encode(id, &ALPHABET33, ENCODED_LEN33)
 }
 
+/// Encodes the id as a fixed width string of 11 characters, `parse_id_base57` restores it exactly
 pub fn to_str_base57(id: i64) -> String {
-    let mut num = id;
-    let mut bytes = Vec::new();
-    let alpha_len = ALPHABET57.len() as i64;
-    while num > 0 {
-        bytes.push(ALPHABET57[(num % alpha_len) as usize]);
+    encode(id, &ALPHABET57, ENCODED_LEN57)
+}
+
+// The id is encoded as u64 so every i64 has a representation,
+// leading zero digits pad it to the fixed width
+fn encode(id: i64, alphabet: &[u8], width: usize) -> String {
+    let mut num = id as u64;
+    let alpha_len = alphabet.len() as u64;
+    let mut bytes = vec![alphabet[0]; width];
+    for byte in bytes.iter_mut().rev() {
+        *byte = alphabet[(num % alpha_len) as usize];
         num /= alpha_len;
     }
-    bytes.reverse();
-    String::from_utf8(bytes).unwrap()
+    String::from_utf8(bytes).unwrap_or_default()
+}
+
+// Strings shorter than the fixed width, as produced before the padding, decode to the same id
+fn decode(s: &str, alphabet: &[u8]) -> Result<i64, ParseIdError> {
+    if s.is_empty() {
+        return Err(ParseIdError::Empty);
+    }
+    let alpha_len = alphabet.len() as u64;
+    let mut num = 0u64;
+    for (position, c) in s.chars().enumerate() {
+        let index = alphabet.iter()
+            .position(|v| *v as char == c)
+            .ok_or(ParseIdError::InvalidCharacter(c, position))? as u64;
+        num = num.checked_mul(alpha_len)
+            .and_then(|v| v.checked_add(index))
+            .ok_or(ParseIdError::Overflow)?;
+    }
+    Ok(num as i64)
 }
 
 #[cfg(test)]
@@ -249,9 +409,143 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_with_config() {
+        let config = SnowflakeConfig {
+            epoch: 1_700_000_000_000,
+            worker_id_bits: 16,
+            sequence_bits: 6,
+        };
+        let snowflake = Snowflake::with_config(40_000, config).unwrap();
+        let first = snowflake.next_id();
+        let second = snowflake.next_id();
+        assert!(second > first);
+        // the worker id sits right above the sequence bits
+        assert_eq!((first >> 6) & 0xffff, 40_000);
+
+        let config = SnowflakeConfig {
+            worker_id_bits: 16,
+            sequence_bits: 12,
+            ..Default::default()
+        };
+        assert!(Snowflake::with_config(1, config).is_err());
+
+        // a worker id out of the layout is rejected rather than wrapped onto another worker
+        let config = SnowflakeConfig { worker_id_bits: 4, ..Default::default() };
+        assert_eq!(Snowflake::with_config(15, config).unwrap().worker_id, 15);
+        let error = Snowflake::with_config(17, config).err().unwrap();
+        assert!(matches!(error, SnowflakeError::WorkerIdOutOfRange(17, 15)));
+        assert_eq!(error.to_string(), "Worker id 17 out of range 0..=15");
+        assert!(Snowflake::with_config(-1, config).is_err());
+    }
+
+    #[test]
+    fn test_decompose() {
+        let before = chrono::Utc::now().timestamp_millis();
+        let snowflake = Snowflake::new(42);
+        let first = snowflake.next_id();
+        let second = snowflake.next_id();
+        let after = chrono::Utc::now().timestamp_millis();
+        for id in [first, second] {
+            assert!((before..=after).contains(&timestamp_of(id)));
+            assert_eq!(worker_id_of(id), 42);
+            assert_eq!((snowflake.timestamp_of(id), snowflake.worker_id_of(id)), (timestamp_of(id), 42));
+        }
+        if timestamp_of(first) == timestamp_of(second) {
+            assert_eq!(sequence_of(second), sequence_of(first) + 1);
+        } else {
+            assert_eq!(sequence_of(second), 0);
+        }
+
+        let config = SnowflakeConfig { epoch: 1_700_000_000_000, worker_id_bits: 16, sequence_bits: 6 };
+        let snowflake = Snowflake::with_config(40_000, config).unwrap();
+        let id = snowflake.next_id();
+        assert!((before..=chrono::Utc::now().timestamp_millis()).contains(&snowflake.timestamp_of(id)));
+        assert_eq!(snowflake.worker_id_of(id), 40_000);
+        assert!(snowflake.sequence_of(id) < 64);
+    }
+
+    #[tokio::test]
+    async fn test_next_id_async() {
+        // 2 ids per millisecond, most calls wait for the next one
+        let config = SnowflakeConfig { sequence_bits: 1, ..Default::default() };
+        let snowflake = Snowflake::with_config(1, config).unwrap();
+        let mut ids = Vec::new();
+        for _ in 0..20 {
+            ids.push(snowflake.next_id_async().await);
+            ids.push(snowflake.next_id());
+        }
+        assert!(ids.windows(2).all(|v| v[0] < v[1]));
+        assert!(generate_id_async().await < generate_id());
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(Snowflake::try_new(5).unwrap().worker_id, 5);
+        assert_eq!(Snowflake::try_new(MAX_WORKER_ID).unwrap().worker_id, MAX_WORKER_ID);
+        assert!(matches!(Snowflake::try_new(MAX_WORKER_ID + 5), Err(SnowflakeError::WorkerIdOutOfRange(1028, MAX_WORKER_ID))));
+        assert!(Snowflake::try_new(-1).is_err());
+        // new keeps wrapping
+        assert_eq!(Snowflake::new(MAX_WORKER_ID + 6).worker_id, 5);
+    }
+
+    #[test]
+    fn test_worker_id_from_ip() {
+        assert_eq!(worker_id_from_ip("10.1.2.3"), (2 << 8) | 3);
+        assert_eq!(worker_id_from_ip("127.0.0.1"), 1);
+        assert_eq!(worker_id_from_ip("::1"), 1);
+        assert_eq!(worker_id_from_ip("fd00::201"), 0x0201);
+        assert_eq!(worker_id_from_ip("fd00::ffff"), (0xffff ^ (0xffff >> 10)) & MAX_WORKER_ID);
+        // invalid addresses fall back to the machine id instead of panicking
+        let fallback = worker_id_from_ip("garbage");
+        assert_eq!(worker_id_from_ip("10.1"), fallback);
+
+        let v4 = ["10.0.0.1", "192.168.255.255", "172.16.3.4", "0.0.0.0"];
+        let v6 = ["::1", "fe80::fc:ff:fe00:1", "2001:db8::ffff:ffff", "::ffff:10.1.2.3", "::"];
+        for ip in v4.iter().chain(v6.iter()) {
+            let worker_id = worker_id_from_ip(ip);
+            assert!(worker_id >= 0, "{ip}");
+            assert!(Snowflake::new(worker_id).worker_id <= MAX_WORKER_ID, "{ip}");
+        }
+        for ip in v6 {
+            assert!(worker_id_from_ip(ip) <= MAX_WORKER_ID, "{ip}");
+        }
+        let snowflake = Snowflake::new(fallback);
+        assert!(snowflake.worker_id <= MAX_WORKER_ID);
+    }
+
     #[test]
     fn test_parse_id() {
         let id = parse_id_base57("3vTErqVS35");
         println!("3vTErqVS35->{id}");
     }
+
+    #[test]
+    fn test_to_str_round_trip() {
+        use rand::Rng;
+
+        let mut rng = rand::rng();
+        let mut ids: Vec<i64> = (0..10_000).map(|_| rng.random_range(0..=i64::MAX)).collect();
+        ids.extend([0, 1, 32, 33, i64::MAX, -1, i64::MIN, generate_id()]);
+        for id in ids {
+            let id_str = to_str(id);
+            assert_eq!(id_str.len(), 13);
+            assert_eq!(try_parse_id(&id_str), Ok(id), "{id} -> {id_str}");
+
+            let id_str = to_str_base57(id);
+            assert_eq!(id_str.len(), 11);
+            assert_eq!(try_parse_id_base57(&id_str), Ok(id), "{id} -> {id_str}");
+        }
+
+        // unpadded strings still decode to the same id
+        assert_eq!(parse_id(to_str(1234).trim_start_matches('2')), 1234);
+    }
+
+    #[test]
+    fn test_try_parse_id() {
+        assert_eq!(try_parse_id(""), Err(ParseIdError::Empty));
+        assert_eq!(try_parse_id("ab1c"), Err(ParseIdError::InvalidCharacter('1', 2)));
+        assert_eq!(try_parse_id_base57("3vTE0"), Err(ParseIdError::InvalidCharacter('0', 4)));
+        assert_eq!(try_parse_id("zzzzzzzzzzzzzz"), Err(ParseIdError::Overflow));
+    }
 }
@@ -2,17 +2,34 @@ pub const ZENOH_MODE: &str = "ZENOH_MODE";
 pub const ZENOH_CONNECT: &str = "ZENOH_CONNECT";
 pub const ZENOH_LISTEN: &str = "ZENOH_LISTEN";
 pub const ZENOH_NO_MULTICAST_SCOUTING: &str = "ZENOH_NO_MULTICAST_SCOUTING";
-pub const ZENOH_NO_GOSSIP_SCOUTING: &str = "ZENOH_NO_MULTICAST_SCOUTING";
+pub const ZENOH_NO_GOSSIP_SCOUTING: &str = "ZENOH_NO_GOSSIP_SCOUTING";
 pub const ZENOH_UNICAST_MAX_LINKS: &str = "ZENOH_UNICAST_MAX_LINKS";
 pub const ZENOH_ENABLE_SHM: &str = "ZENOH_ENABLE_SHM";
+pub const ZENOH_CONFIG_FILE: &str = "ZENOH_CONFIG_FILE";
+pub const ZENOH_RECONNECT_MAX_MS: &str = "ZENOH_RECONNECT_MAX_MS";
 pub const SERVER_BIND: &str = "SERVER_BIND";
 pub const SERVER_ALLOW_ORIGINS: &str = "SERVER_ALLOW_ORIGINS";
+pub const SERVER_ALLOW_METHODS: &str = "SERVER_ALLOW_METHODS";
+pub const SERVER_ALLOW_HEADERS: &str = "SERVER_ALLOW_HEADERS";
+pub const SERVER_REQUIRED_SERVICES: &str = "SERVER_REQUIRED_SERVICES";
+pub const SERVER_SHUTDOWN_TIMEOUT_SECONDS: &str = "SERVER_SHUTDOWN_TIMEOUT_SECONDS";
+pub const SERVER_TRUSTED_PROXIES: &str = "SERVER_TRUSTED_PROXIES";
+pub const SECURITY_PROFILE: &str = "SECURITY_PROFILE";
+pub const SECURITY_DISABLE_HEADERS: &str = "SECURITY_DISABLE_HEADERS";
+pub const SECURITY_PROTO_HEADER: &str = "SECURITY_PROTO_HEADER";
 pub const ACCESS_TOKEN_DURATION: &str = "ACCESS_TOKEN_DURATION";
-pub const SERVER_ID: &str = "ACCESS_TOKEN_DURATION";
+pub const REFRESH_TOKEN_DURATION: &str = "REFRESH_TOKEN_DURATION";
+pub const SERVER_ID: &str = "SERVER_ID";
+pub const JWT_SECRET: &str = "JWT_SECRET";
+pub const JWT_LEEWAY_SECONDS: &str = "JWT_LEEWAY_SECONDS";
+pub const RATE_LIMIT_CAPACITY: &str = "RATE_LIMIT_CAPACITY";
+pub const RATE_LIMIT_REFILL: &str = "RATE_LIMIT_REFILL";
+pub const WS_BUFFER_SIZE: &str = "WS_BUFFER_SIZE";
+pub const WS_OVERFLOW_POLICY: &str = "WS_OVERFLOW_POLICY";
+pub const WS_PUBLIC_SERVICES: &str = "WS_PUBLIC_SERVICES";
 
 pub fn get_env_var<T: std::str::FromStr>(key: &str, default: T) -> T {
-    std::env::var(key)
-        .ok()
+    var(key)
         .and_then(|val| val.parse::<T>().ok())
         .unwrap_or(default)
 }
@@ -25,14 +42,147 @@ pub fn get_allow_origins()-> String {
     get_env_var(SERVER_ALLOW_ORIGINS, "*".to_string()).replace(";", " ").replace(",", " ")
 }
 
+/// Methods allowed by CORS on top of the defaults, separated by commas, semicolons or spaces
+pub fn get_allow_methods()-> Vec<String> {
+    split_list(&get_env_var(SERVER_ALLOW_METHODS, "".to_string()))
+}
+
+/// Headers allowed by CORS on top of the defaults, e.g. `x-tenant-id`, separated by commas, semicolons or spaces
+pub fn get_allow_headers()-> Vec<String> {
+    split_list(&get_env_var(SERVER_ALLOW_HEADERS, "".to_string()))
+}
+
+/// Services the gateway needs an instance of before `/ready` reports ready, separated by commas, semicolons or spaces
+pub fn get_required_services()-> Vec<String> {
+    split_list(&get_env_var(SERVER_REQUIRED_SERVICES, "".to_string()))
+}
+
+/// Seconds the gateway waits for the requests in flight once it got the shutdown signal, before closing them
+pub fn get_shutdown_timeout()-> u64 {
+    get_env_var(SERVER_SHUTDOWN_TIMEOUT_SECONDS, 20)
+}
+
+/// Addresses or CIDR ranges of the proxies in front of the gateway, e.g. `10.0.0.0/8`, separated by commas, semicolons or spaces,
+/// the client address is only read from the forwarded headers of the requests they make
+pub fn get_trusted_proxies()-> Vec<String> {
+    split_list(&get_env_var(SERVER_TRUSTED_PROXIES, "".to_string()))
+}
+
+/// Security headers preset of the gateway, `production` (default) or `default`, which allows inline scripts and styles
+pub fn get_security_profile()-> String {
+    get_env_var(SECURITY_PROFILE, "production".to_string())
+}
+
+/// Names of the security headers the gateway doesn't send, e.g. `strict-transport-security`,
+/// separated by commas, semicolons or spaces
+pub fn get_security_disable_headers()-> Vec<String> {
+    split_list(&get_env_var(SECURITY_DISABLE_HEADERS, "".to_string()))
+}
+
+/// Header the trusted proxy terminating TLS sets to the scheme of the client request,
+/// only read from the requests of `SERVER_TRUSTED_PROXIES`
+pub fn get_security_proto_header()-> String {
+    get_env_var(SECURITY_PROTO_HEADER, "x-forwarded-proto".to_string())
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split([',', ';', ' '])
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect()
+}
+
 pub fn get_jwt_duration()-> i64 {
     get_env_var(ACCESS_TOKEN_DURATION, 3600)
 }
 
+/// Seconds a refresh token stays valid, 30 days by default
+pub fn get_refresh_token_duration()-> i64 {
+    get_env_var(REFRESH_TOKEN_DURATION, 30 * 24 * 3600)
+}
+
+pub fn get_jwt_secret()-> String {
+    get_env_var(JWT_SECRET, "".to_string())
+}
+
+/// Clock skew tolerated on the `exp` and `nbf` of the verified tokens, in seconds
+pub fn get_jwt_leeway()-> u64 {
+    get_env_var(JWT_LEEWAY_SECONDS, 5)
+}
+
+/// Burst size of the per client rate limit
+pub fn get_rate_limit_capacity()-> u32 {
+    get_env_var(RATE_LIMIT_CAPACITY, 100)
+}
+
+/// Requests per second a client regains after a burst
+pub fn get_rate_limit_refill()-> f64 {
+    get_env_var(RATE_LIMIT_REFILL, 20.0)
+}
+
+/// Messages queued for a slow websocket client before `WS_OVERFLOW_POLICY` applies
+pub fn get_ws_buffer_size()-> usize {
+    get_env_var(WS_BUFFER_SIZE, 64)
+}
+
+/// What a full websocket buffer does, `drop_oldest` (default) or `close`
+pub fn get_ws_overflow_policy()-> crate::outbox::OverflowPolicy {
+    get_env_var(WS_OVERFLOW_POLICY, crate::outbox::OverflowPolicy::DropOldest)
+}
+
+/// Services whose pushes any websocket may subscribe to, separated by commas, semicolons or spaces,
+/// the sockets only get the pushes of their own user from the others
+pub fn get_ws_public_services()-> Vec<String> {
+    split_list(&get_env_var(WS_PUBLIC_SERVICES, "".to_string()))
+}
+
 pub fn get_server_id() -> Option<i64> {
-    std::env::var(SERVER_ID)
-        .ok()
+    var(SERVER_ID)
         .and_then(|val| val.parse::<i64>().ok())
 }
 
+/// Value of the env var, tests override it for their own thread, see `tests::with_vars`
+fn var(key: &str) -> Option<String> {
+    #[cfg(test)]
+    if let Some(value) = tests::OVERRIDES.with(|v| v.borrow().get(key).cloned()) {
+        return Some(value);
+    }
+    std::env::var(key).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    thread_local! {
+        /// Vars of the test running on the thread, read before the env
+        pub(super) static OVERRIDES: std::cell::RefCell<std::collections::HashMap<String, String>> = Default::default();
+    }
+
+    /// Runs `f` with the vars overridden for the calling thread only,
+    /// the env is left untouched, setting it while other tests of the binary read it is undefined behavior
+    fn with_vars(vars: &[(&str, &str)], f: impl FnOnce()) {
+        OVERRIDES.with(|v| v.borrow_mut().extend(vars.iter().map(|(key, value)| (key.to_string(), value.to_string()))));
+        f();
+        OVERRIDES.with(|v| v.borrow_mut().clear());
+    }
 
+    #[test]
+    fn test_server_id() {
+        with_vars(&[(SERVER_ID, "42"), (ACCESS_TOKEN_DURATION, "7200"), (REFRESH_TOKEN_DURATION, "86400")], || {
+            assert_eq!(get_server_id(), Some(42));
+            assert_eq!(get_jwt_duration(), 7200);
+            assert_eq!(get_refresh_token_duration(), 86400);
+        });
+    }
+
+    #[test]
+    fn test_allow_lists() {
+        with_vars(&[(SERVER_ALLOW_METHODS, "PUT, HEAD"), (SERVER_ALLOW_HEADERS, "x-tenant-id;x-client-version ")], || {
+            assert_eq!(get_allow_methods(), ["PUT", "HEAD"]);
+            assert_eq!(get_allow_headers(), ["x-tenant-id", "x-client-version"]);
+        });
+        assert!(split_list(" ,; ").is_empty());
+    }
+}
@@ -19,6 +19,8 @@ pub struct Generator {
     counter: AtomicU32,
     machine_id: [u8; 3],
     pid: [u8; 2],
+    // latest timestamp used, ids never get an earlier one even if the clock steps back
+    last_ts: AtomicU32,
 }
 
 pub fn get_generator() -> &'static Generator {
@@ -28,6 +30,7 @@ pub fn get_generator() -> &'static Generator {
         counter: AtomicU32::new(init_random()),
         machine_id: get_machine_id(),
         pid: get_pid().to_be_bytes(),
+        last_ts: AtomicU32::new(0),
     })
 }
 
@@ -36,18 +39,44 @@ impl Generator {
         self.with_time(&SystemTime::now())
     }
 
+    /// Generates `n` ids sharing one timestamp and a contiguous block of counter values.
+    /// The 3-byte counter only has room for 2^24 distinct ids per second,
+    /// larger batches are split and each part waits for a later second.
+    pub fn new_ids(&self, n: usize) -> Vec<Id> {
+        let mut ids = Vec::with_capacity(n);
+        let mut last_ts = None;
+        while ids.len() < n {
+            let mut unix_ts = self.monotonic(unix_seconds(&SystemTime::now()));
+            while last_ts.is_some_and(|v| unix_ts <= v) {
+                std::thread::sleep(Duration::from_millis(10));
+                unix_ts = self.monotonic(unix_seconds(&SystemTime::now()));
+            }
+            #[allow(clippy::cast_possible_truncation)]
+            let count = (n - ids.len()).min(COUNTER_SPACE as usize) as u32;
+            let first = self.counter.fetch_add(count, Ordering::SeqCst);
+            ids.extend((0..count).map(|i| self.assemble(unix_ts, first.wrapping_add(i))));
+            last_ts = Some(unix_ts);
+        }
+        ids
+    }
+
     fn with_time(&self, time: &SystemTime) -> Id {
-        // Panic if the time is before the epoch.
-        let unix_ts = time
-            .duration_since(UNIX_EPOCH)
-            .expect("Clock may have gone backwards");
-        #[allow(clippy::cast_possible_truncation)]
-        self.generate(unix_ts.as_secs() as u32)
+        self.generate(unix_seconds(time))
     }
 
     fn generate(&self, unix_ts: u32) -> Id {
+        let unix_ts = self.monotonic(unix_ts);
         let counter = self.counter.fetch_add(1, Ordering::SeqCst);
+        self.assemble(unix_ts, counter)
+    }
 
+    /// Clamps the timestamp to the latest one used, so ids stay sorted by time when the clock steps back,
+    /// e.g. on an NTP correction. The counter keeps them distinct within the clamped second.
+    fn monotonic(&self, unix_ts: u32) -> u32 {
+        self.last_ts.fetch_max(unix_ts, Ordering::SeqCst).max(unix_ts)
+    }
+
+    fn assemble(&self, unix_ts: u32, counter: u32) -> Id {
         let mut raw = [0_u8; RAW_LEN];
         // 4 bytes of Timestamp (big endian)
         raw[0..=3].copy_from_slice(&unix_ts.to_be_bytes());
@@ -62,6 +91,19 @@ impl Generator {
     }
 }
 
+fn unix_seconds(time: &SystemTime) -> u32 {
+    // a time before the epoch counts as the epoch, the latest timestamp used applies then
+    let unix_ts = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    #[allow(clippy::cast_possible_truncation)]
+    let unix_ts = unix_ts.as_secs() as u32;
+    unix_ts
+}
+
+// Number of distinct values of the 3-byte counter
+const COUNTER_SPACE: u32 = 1 << 24;
+
 // https://github.com/rs/xid/blob/efa678f304ab65d6d57eedcb086798381ae22206/id.go#L136
 fn init_random() -> u32 {
     let mut bs = [0_u8; 3];
@@ -70,6 +112,7 @@ fn init_random() -> u32 {
 }
 
 pub(crate) const RAW_LEN: usize = 12;
+const UUID_LEN: usize = 16;
 const ENCODED_LEN: usize = 20;
 const ENC: &[u8] = "0123456789abcdefghijklmnopqrstuv".as_bytes();
 
@@ -108,6 +151,8 @@ pub enum DecodeError {
     InvalidLength(usize),
     #[error("Invalid character '{0}' at position {1}")]
     InvalidCharacter(char, usize),
+    #[error("Invalid length: expected 12 bytes, got {0}")]
+    InvalidRawLength(usize),
 }
 
 impl std::str::FromStr for Id {
@@ -148,6 +193,24 @@ fn decode_char(c: u8) -> Result<u8, DecodeError> {
 }
 
 impl Id {
+    /// Builds an id from its binary representation.
+    #[must_use]
+    pub const fn from_bytes(raw: [u8; RAW_LEN]) -> Self {
+        Id(raw)
+    }
+
+    /// Builds an id from a binary representation of unchecked length.
+    pub fn from_slice(raw: &[u8]) -> Result<Self, DecodeError> {
+        let raw: [u8; RAW_LEN] = raw.try_into().map_err(|_| DecodeError::InvalidRawLength(raw.len()))?;
+        Ok(Id(raw))
+    }
+
+    /// A copy of the binary representation of the id.
+    #[must_use]
+    pub const fn to_raw(&self) -> [u8; RAW_LEN] {
+        self.0
+    }
+
     /// The binary representation of the id.
     #[must_use]
     pub fn as_bytes(&self) -> &[u8; RAW_LEN] {
@@ -172,9 +235,24 @@ impl Id {
     /// Extract the timestamp.
     #[must_use]
     pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(u64::from(self.unix_ts()))
+    }
+
+    /// The creation time as a local datetime string, in the time zone of `SERVICE_TZ`.
+    #[must_use]
+    pub fn created_at_local(&self) -> String {
+        crate::get_local_datetime_formarted(i64::from(self.unix_ts()))
+    }
+
+    /// Time elapsed since the creation, zero for an id created ahead of the local clock.
+    #[must_use]
+    pub fn age(&self) -> Duration {
+        SystemTime::now().duration_since(self.time()).unwrap_or_default()
+    }
+
+    fn unix_ts(&self) -> u32 {
         let raw = self.as_bytes();
-        let unix_ts = u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]);
-        UNIX_EPOCH + Duration::from_secs(u64::from(unix_ts))
+        u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]])
     }
 
     /// Extract the incrementing counter.
@@ -184,6 +262,25 @@ impl Id {
         let raw = self.as_bytes();
         u32::from_be_bytes([0, raw[9], raw[10], raw[11]])
     }
+
+    /// The id as a UUID, its raw bytes followed by 4 zero bytes.
+    /// UUIDs compare by their bytes, so the converted ids keep their order.
+    #[must_use]
+    pub fn to_uuid(&self) -> uuid::Uuid {
+        let mut bytes = [0_u8; UUID_LEN];
+        bytes[..RAW_LEN].copy_from_slice(self.as_bytes());
+        uuid::Uuid::from_bytes(bytes)
+    }
+
+    /// The id converted by `to_uuid`, none for a UUID not ending with its 4 zero bytes.
+    #[must_use]
+    pub fn from_uuid(uuid: &uuid::Uuid) -> Option<Self> {
+        let (raw, padding) = uuid.as_bytes().split_at(RAW_LEN);
+        if padding.iter().any(|v| *v != 0) {
+            return None;
+        }
+        Self::from_slice(raw).ok()
+    }
 }
 
 impl Display for Id {
@@ -214,38 +311,6 @@ impl Display for Id {
     }
 }
 
-/* 
-impl ToString for Id {
-    // https://github.com/rs/xid/blob/efa678f304ab65d6d57eedcb086798381ae22206/id.go#L208
-    /// Returns the string representation of the id.
-    fn to_string(&self) -> String {
-        let Self(raw) = self;
-        let mut bs = [0_u8; ENCODED_LEN];
-        bs[19] = ENC[((raw[11] << 4) & 31) as usize];
-        bs[18] = ENC[((raw[11] >> 1) & 31) as usize];
-        bs[17] = ENC[(((raw[11] >> 6) | (raw[10] << 2)) & 31) as usize];
-        bs[16] = ENC[(raw[10] >> 3) as usize];
-        bs[15] = ENC[(raw[9] & 31) as usize];
-        bs[14] = ENC[(((raw[9] >> 5) | (raw[8] << 3)) & 31) as usize];
-        bs[13] = ENC[((raw[8] >> 2) & 31) as usize];
-        bs[12] = ENC[(((raw[8] >> 7) | (raw[7] << 1)) & 31) as usize];
-        bs[11] = ENC[(((raw[7] >> 4) | (raw[6] << 4)) & 31) as usize];
-        bs[10] = ENC[((raw[6] >> 1) & 31) as usize];
-        bs[9] = ENC[(((raw[6] >> 6) | (raw[5] << 2)) & 31) as usize];
-        bs[8] = ENC[(raw[5] >> 3) as usize];
-        bs[7] = ENC[(raw[4] & 31) as usize];
-        bs[6] = ENC[(((raw[4] >> 5) | (raw[3] << 3)) & 31) as usize];
-        bs[5] = ENC[((raw[3] >> 2) & 31) as usize];
-        bs[4] = ENC[(((raw[3] >> 7) | (raw[2] << 1)) & 31) as usize];
-        bs[3] = ENC[(((raw[2] >> 4) | (raw[1] << 4)) & 31) as usize];
-        bs[2] = ENC[((raw[1] >> 1) & 31) as usize];
-        bs[1] = ENC[(((raw[1] >> 6) | (raw[0] << 2)) & 31) as usize];
-        bs[0] = ENC[(raw[0] >> 3) as usize];
-        str::from_utf8(&bs).unwrap().to_string()
-    }
-}
-*/
-
 // 2 bytes of PID
 // https://github.com/rs/xid/blob/efa678f304ab65d6d57eedcb086798381ae22206/id.go#L159
 #[allow(clippy::cast_possible_truncation)]
@@ -274,6 +339,12 @@ pub fn new() -> Id {
     get_generator().new_id()
 }
 
+/// Generate `n` new globally unique ids in one go.
+#[must_use]
+pub fn new_batch(n: usize) -> Vec<Id> {
+    get_generator().new_ids(n)
+}
+
 // https://github.com/rs/xid/blob/efa678f304ab65d6d57eedcb086798381ae22206/id.go#L117
 pub fn get_machine_id() -> [u8; 3] {
     let id = match machine_id().unwrap_or_default() {
@@ -380,4 +451,94 @@ mod tests {
         let result = invalid_str.parse::<super::Id>();
         assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_from_bytes() {
+        let id = super::new();
+        assert_eq!(super::Id::from_bytes(id.to_raw()), id);
+        assert_eq!(super::Id::from_slice(id.as_bytes()).unwrap(), id);
+        assert_eq!(super::Id::from_bytes(id.to_raw()).to_string(), id.to_string());
+
+        let result = super::Id::from_slice(&id.to_raw()[1..]);
+        assert!(matches!(result, Err(super::DecodeError::InvalidRawLength(11))));
+    }
+
+    #[test]
+    fn test_clock_step_back() {
+        let generator = super::Generator {
+            counter: super::AtomicU32::new(0),
+            machine_id: super::get_machine_id(),
+            pid: super::get_pid().to_be_bytes(),
+            last_ts: super::AtomicU32::new(0),
+        };
+        let now = std::time::SystemTime::now();
+        let mut ids = vec![generator.with_time(&now)];
+        // the clock steps back 10 seconds, then catches up
+        for step in [10, 5, 0] {
+            ids.push(generator.with_time(&(now - std::time::Duration::from_secs(step))));
+        }
+        ids.push(generator.with_time(&(now + std::time::Duration::from_secs(1))));
+        ids.push(generator.with_time(&std::time::UNIX_EPOCH.checked_sub(std::time::Duration::from_secs(1)).unwrap()));
+
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(sorted, ids);
+        assert!(ids.windows(2).all(|v| v[0].time() <= v[1].time()));
+        assert_eq!(ids[3].time(), ids[0].time());
+    }
+
+    #[test]
+    fn test_creation_time() {
+        let mut raw = super::new().to_raw();
+        raw[..4].copy_from_slice(&1_600_000_000u32.to_be_bytes());
+        let id = super::Id::from_bytes(raw);
+        assert_eq!(id.created_at_local(), crate::get_local_datetime_formarted(1_600_000_000));
+        assert!(id.age() > std::time::Duration::from_secs(365 * 24 * 3600));
+
+        // an id from ahead of the clock has no age yet
+        raw[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(super::Id::from_bytes(raw).age(), std::time::Duration::ZERO);
+        assert!(super::new().age() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_uuid_round_trip() {
+        let ids = super::new_batch(100);
+        for id in &ids {
+            let uuid = id.to_uuid();
+            assert_eq!(&uuid.as_bytes()[..12], id.as_bytes());
+            assert_eq!(super::Id::from_uuid(&uuid), Some(*id));
+        }
+        // sorted ids give sorted uuids
+        let mut sorted = ids.clone();
+        sorted.sort();
+        let uuids: Vec<_> = sorted.iter().map(super::Id::to_uuid).collect();
+        assert!(uuids.windows(2).all(|v| v[0] < v[1]));
+
+        let edge = super::Id::from_bytes([0xff; 12]);
+        assert_eq!(super::Id::from_uuid(&edge.to_uuid()), Some(edge));
+        // the uuids not made from an id keep their last bytes
+        assert_eq!(super::Id::from_uuid(&uuid::Uuid::from_u128(1)), None);
+        assert_eq!(super::Id::from_uuid(&uuid::Uuid::max()), None);
+        assert_eq!(super::Id::from_uuid(&uuid::Uuid::nil()), Some(super::Id::from_bytes([0; 12])));
+    }
+
+    #[test]
+    fn test_new_batch() {
+        let ids = super::new_batch(1000);
+        assert_eq!(ids.len(), 1000);
+        for pair in ids.windows(2) {
+            assert_eq!(pair[0].time(), pair[1].time());
+            assert_eq!(pair[1].counter(), (pair[0].counter() + 1) % super::COUNTER_SPACE);
+        }
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+
+        // ids of the generator keep counting after the batch
+        let next = super::new();
+        assert_ne!(next.counter(), ids[999].counter());
+        assert!(super::new_batch(0).is_empty());
+    }
+}
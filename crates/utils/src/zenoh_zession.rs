@@ -1,88 +1,200 @@
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc};
 
 use serde_json::json;
+use tokio::sync::OnceCell;
 
-use crate::vars::{ZENOH_CONNECT, ZENOH_ENABLE_SHM, ZENOH_LISTEN, ZENOH_MODE, ZENOH_NO_GOSSIP_SCOUTING, ZENOH_NO_MULTICAST_SCOUTING, ZENOH_UNICAST_MAX_LINKS};
+use crate::vars::{ZENOH_CONFIG_FILE, ZENOH_CONNECT, ZENOH_ENABLE_SHM, ZENOH_LISTEN, ZENOH_MODE, ZENOH_NO_GOSSIP_SCOUTING, ZENOH_NO_MULTICAST_SCOUTING, ZENOH_RECONNECT_MAX_MS, ZENOH_UNICAST_MAX_LINKS};
 
+/// Error of creating the zenoh session
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("invalid zenoh config file {0}: {1}")]
+    Config(String, zenoh::Error),
+    #[error("failed to open zenoh session: {0}")]
+    Open(zenoh::Error),
+}
+
+/// Session shared by the contexts and nodes of the process, opened on first use
+static SHARED_SESSION: OnceCell<Arc<zenoh::Session>> = OnceCell::const_new();
+
+/// Session shared by the process, so several contexts and nodes reuse one transport,
+/// exits the process with `EXIT_START_NODE_ERROR` on failure
+pub async fn shared_session() -> Arc<zenoh::Session> {
+    match try_shared_session().await {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+            std::process::exit(crate::EXIT_START_NODE_ERROR);
+        }
+    }
+}
+
+/// Session shared by the process, opened by `try_create_session` on the first call, retried by the next calls if it failed
+/// Closing it closes it for every user. `create_session` still opens an isolated session, e.g. for the tests
+/// whose nodes need their own zid
+pub async fn try_shared_session() -> Result<Arc<zenoh::Session>, SessionError> {
+    SHARED_SESSION.get_or_try_init(|| async { try_create_session().await.map(Arc::new) }).await.cloned()
+}
+
+/// Creates the zenoh session of the node, exits the process with `EXIT_START_NODE_ERROR` on failure
 pub async fn create_session() -> zenoh::Session {
+    match try_create_session().await {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+            std::process::exit(crate::EXIT_START_NODE_ERROR);
+        }
+    }
+}
+
+/// Creates the zenoh session of the node
+/// - `ZENOH_CONFIG` loads a full zenoh config file, used as is
+/// - Otherwise `ZENOH_CONFIG_FILE` loads the base config, e.g. the TLS or usrpwd credentials of a secured router,
+///   and the `ZENOH_*` env vars override its values
+pub async fn try_create_session() -> Result<zenoh::Session, SessionError> {
     let config = match zenoh::Config::from_env() {
         Ok(v) => v,
         Err(_) => {
-            let mut config = zenoh::Config::default();
-            if let Ok(mode) = std::env::var(ZENOH_MODE) {
-                let mode = match zenoh::config::WhatAmI::from_str(&mode) {
-                    Ok(v) => v,
-                    Err(_) => zenoh::config::WhatAmI::Peer,
-                };
-
-                if let Err(e) = config.insert_json5("mode", &json!(mode).to_string()) {
-                    tracing::error!("{}:{} {}", file!(), line!(), e);
-                }
-            }
-
-            if let Ok(connect) = std::env::var(ZENOH_CONNECT) {
-                let connect: Vec<String> = connect.split(",").map(|s| s.to_string()).collect();
-                if let Err(e) =
-                    config.insert_json5("connect/endpoints", &json!(connect).to_string())
-                {
-                    tracing::error!("{}:{} {}", file!(), line!(), e);
-                }
-            }
-            if let Ok(listen) = std::env::var(ZENOH_LISTEN) {
-                let listen: Vec<String> = listen.split(",").map(|s| s.to_string()).collect();
-                if let Err(e) = config.insert_json5("listen/endpoints", &json!(listen).to_string())
-                {
-                    tracing::error!("{}:{} {}", file!(), line!(), e);
-                }
-            }
-            if let Ok(is_closed) = std::env::var(ZENOH_NO_MULTICAST_SCOUTING) {
-                let is_closed: i8 = is_closed.parse().unwrap_or_default();
-                if let Err(e) = config.insert_json5(
-                    "scouting/multicast/enabled",
-                    &json!(is_closed == 0).to_string(),
-                ) {
-                    tracing::error!("{}:{} {}", file!(), line!(), e);
-                }
-            }
-
-            if let Ok(is_closed) = std::env::var(ZENOH_NO_GOSSIP_SCOUTING) {
-                let is_closed: i8 = is_closed.parse().unwrap_or_default();
-                if let Err(e) = config.insert_json5(
-                    "scouting/gossip/enabled",
-                    &json!(is_closed == 0).to_string(),
-                ) {
-                    tracing::error!("{}:{} {}", file!(), line!(), e);
-                }
-            }
-
-            if let Ok(links) = std::env::var(ZENOH_UNICAST_MAX_LINKS) {
-                let links: i32 = links.parse().unwrap_or(255);
-                if let Err(e) =
-                    config.insert_json5("transport/unicast/max_links", &json!(links).to_string())
-                {
-                    tracing::error!("{}:{} {}", file!(), line!(), e);
-                }
-            }
-
-            if let Ok(is_open) = std::env::var(ZENOH_ENABLE_SHM) {
-                let is_open: i8 = is_open.parse().unwrap_or_default();
-                if let Err(e) = config.insert_json5(
-                    "transport/shared_memory/enabled",
-                    &json!(is_open).to_string(),
-                ) {
-                    tracing::error!("{}:{} {}", file!(), line!(), e);
-                }
-            }
+            let mut config = match std::env::var(ZENOH_CONFIG_FILE) {
+                Ok(path) => zenoh::Config::from_file(&path).map_err(|e| SessionError::Config(path, e))?,
+                Err(_) => zenoh::Config::default(),
+            };
+            apply_overrides(&mut config, |key| std::env::var(key).ok());
             config
         }
     };
     tracing::info!("[cluster] start service with config: {}", config);
 
-    match zenoh::open(config).await {
-        Ok(v) => v,
-        Err(e) => {
+    zenoh::open(config).await.map_err(SessionError::Open)
+}
+
+/// Overrides the values of the config with the `ZENOH_*` variables found by `get`
+fn apply_overrides(config: &mut zenoh::Config, get: impl Fn(&str) -> Option<String>) {
+    if let Some(mode) = get(ZENOH_MODE) {
+        let mode = match zenoh::config::WhatAmI::from_str(&mode) {
+            Ok(v) => v,
+            Err(_) => zenoh::config::WhatAmI::Peer,
+        };
+
+        if let Err(e) = config.insert_json5("mode", &json!(mode).to_string()) {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+        }
+    }
+
+    if let Some(connect) = get(ZENOH_CONNECT) {
+        let connect: Vec<String> = connect.split(",").map(|s| s.to_string()).collect();
+        if let Err(e) =
+            config.insert_json5("connect/endpoints", &json!(connect).to_string())
+        {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+        }
+    }
+    // zenoh retries the connect endpoints it can't reach or lost, waiting twice as long after each failure
+    if let Some(max_ms) = get(ZENOH_RECONNECT_MAX_MS) {
+        let max_ms: i64 = max_ms.parse().unwrap_or(4000);
+        let retry = json!({ "period_init_ms": max_ms.min(1000), "period_max_ms": max_ms, "period_increase_factor": 2.0 });
+        if let Err(e) = config.insert_json5("connect/retry", &retry.to_string()) {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+        }
+    }
+    if let Some(listen) = get(ZENOH_LISTEN) {
+        let listen: Vec<String> = listen.split(",").map(|s| s.to_string()).collect();
+        if let Err(e) = config.insert_json5("listen/endpoints", &json!(listen).to_string())
+        {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+        }
+    }
+    if let Some(is_closed) = get(ZENOH_NO_MULTICAST_SCOUTING) {
+        let is_closed: i8 = is_closed.parse().unwrap_or_default();
+        if let Err(e) = config.insert_json5(
+            "scouting/multicast/enabled",
+            &json!(is_closed == 0).to_string(),
+        ) {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+        }
+    }
+
+    if let Some(is_closed) = get(ZENOH_NO_GOSSIP_SCOUTING) {
+        let is_closed: i8 = is_closed.parse().unwrap_or_default();
+        if let Err(e) = config.insert_json5(
+            "scouting/gossip/enabled",
+            &json!(is_closed == 0).to_string(),
+        ) {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+        }
+    }
+
+    if let Some(links) = get(ZENOH_UNICAST_MAX_LINKS) {
+        let links: i32 = links.parse().unwrap_or(255);
+        if let Err(e) =
+            config.insert_json5("transport/unicast/max_links", &json!(links).to_string())
+        {
             tracing::error!("{}:{} {}", file!(), line!(), e);
-            std::process::exit(crate::EXIT_START_NODE_ERROR);
         }
     }
+
+    if let Some(is_open) = get(ZENOH_ENABLE_SHM) {
+        let is_open: i8 = is_open.parse().unwrap_or_default();
+        if let Err(e) = config.insert_json5(
+            "transport/shared_memory/enabled",
+            &json!(is_open).to_string(),
+        ) {
+            tracing::error!("{}:{} {}", file!(), line!(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file_with_overrides() {
+        let path = std::env::temp_dir().join(format!("zenoh_config_{}.json5", std::process::id()));
+        std::fs::write(&path, r#"{
+            mode: "client",
+            connect: { endpoints: ["tcp/10.0.0.1:7447"] },
+            listen: { endpoints: ["tcp/0.0.0.0:7447"] },
+        }"#).unwrap();
+        let mut config = zenoh::Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        apply_overrides(&mut config, |key| (key == ZENOH_CONNECT).then(|| "tcp/10.0.0.2:7447,tcp/10.0.0.3:7447".to_string()));
+        // the env vars take precedence, values they don't set are kept from the file
+        assert_eq!(config.get_json("mode").unwrap(), r#""client""#);
+        assert_eq!(config.get_json("connect/endpoints").unwrap(), r#"["tcp/10.0.0.2:7447","tcp/10.0.0.3:7447"]"#);
+        assert_eq!(config.get_json("listen/endpoints").unwrap(), r#"["tcp/0.0.0.0:7447"]"#);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_shared_session() {
+        let session = shared_session().await;
+        assert!(Arc::ptr_eq(&session, &shared_session().await));
+        let isolated = create_session().await;
+        assert_ne!(isolated.zid(), session.zid());
+        isolated.close().await.unwrap();
+        assert!(!session.is_closed());
+    }
+
+    #[test]
+    fn test_scouting_vars() {
+        let mut config = zenoh::Config::default();
+        let multicast = config.get_json("scouting/multicast/enabled").unwrap();
+        apply_overrides(&mut config, |key| (key == ZENOH_NO_GOSSIP_SCOUTING).then(|| "1".to_string()));
+        assert_eq!(config.get_json("scouting/gossip/enabled").unwrap(), "false");
+        assert_eq!(config.get_json("scouting/multicast/enabled").unwrap(), multicast);
+
+        apply_overrides(&mut config, |key| (key == ZENOH_NO_MULTICAST_SCOUTING).then(|| "1".to_string()));
+        assert_eq!(config.get_json("scouting/multicast/enabled").unwrap(), "false");
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let mut config = zenoh::Config::default();
+        apply_overrides(&mut config, |key| (key == ZENOH_RECONNECT_MAX_MS).then(|| "30000".to_string()));
+        let retry: serde_json::Value = serde_json::from_str(&config.get_json("connect/retry").unwrap()).unwrap();
+        assert_eq!(retry["period_init_ms"], 1000);
+        assert_eq!(retry["period_max_ms"], 30000);
+        assert_eq!(retry["period_increase_factor"], 2.0);
+    }
 }